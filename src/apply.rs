@@ -0,0 +1,109 @@
+use log::info;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// A change to the firewall, resolving to a message for the caller on failure.
+pub type Work = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+
+enum Job {
+    Reload,
+    Change(Work),
+}
+
+struct Request {
+    job: Job,
+    reply: oneshot::Sender<Result<(), String>>,
+}
+
+/// Handle for submitting changes. Every mutation of the ei chains and sets goes
+/// through here, so reloads, port changes and list refreshes never interleave.
+#[derive(Clone)]
+pub struct ApplyQueue {
+    tx: mpsc::UnboundedSender<Request>,
+}
+
+/// The single worker applying whatever was submitted to its `ApplyQueue`, in order.
+pub struct ApplyWorker {
+    rx: mpsc::UnboundedReceiver<Request>,
+}
+
+pub fn channel() -> (ApplyQueue, ApplyWorker) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (ApplyQueue { tx }, ApplyWorker { rx })
+}
+
+impl ApplyQueue {
+    async fn submit(&self, job: Job) -> Result<(), String> {
+        let (reply, result) = oneshot::channel();
+        self.tx
+            .send(Request { job, reply })
+            .map_err(|_| "Apply worker is not running".to_string())?;
+        result
+            .await
+            .map_err(|_| "Apply worker stopped before applying the change".to_string())?
+    }
+
+    /// Reload the configuration, resolving once this or a later reload has been applied.
+    pub async fn reload(&self) -> Result<(), String> {
+        self.submit(Job::Reload).await
+    }
+
+    /// Run `work` once everything submitted before it has been applied.
+    pub async fn run<F>(&self, work: F) -> Result<(), String>
+    where
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.submit(Job::Change(Box::pin(work))).await
+    }
+}
+
+impl ApplyWorker {
+    /// Start applying jobs. Reloads queued back to back are coalesced into a
+    /// single `reload` call whose result goes to every one of their callers.
+    pub fn spawn<F>(mut self, reload: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Work + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut next = None;
+            loop {
+                let request = match next.take() {
+                    Some(request) => request,
+                    None => match self.rx.recv().await {
+                        Some(request) => request,
+                        None => break,
+                    },
+                };
+
+                match request.job {
+                    Job::Change(work) => {
+                        let _ = request.reply.send(work.await);
+                    }
+                    Job::Reload => {
+                        let mut waiters = vec![request.reply];
+                        while let Ok(queued) = self.rx.try_recv() {
+                            match queued.job {
+                                Job::Reload => waiters.push(queued.reply),
+                                // Anything else keeps its place after this reload
+                                Job::Change(_) => {
+                                    next = Some(queued);
+                                    break;
+                                }
+                            }
+                        }
+                        if waiters.len() > 1 {
+                            info!("Coalescing {} queued reloads into one", waiters.len());
+                        }
+
+                        let result = reload().await;
+                        for waiter in waiters {
+                            let _ = waiter.send(result.clone());
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,42 @@
+use crate::backend::Family;
+use crate::error::Result;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Last good copy of every fetched list, kept so a boot without network
+/// still comes up with the lists it had before.
+#[derive(Debug, Clone)]
+pub struct IpListCache {
+    dir: PathBuf,
+}
+
+impl IpListCache {
+    pub fn new(dir: PathBuf) -> Self {
+        IpListCache { dir }
+    }
+
+    fn path(&self, name: &str, family: Family) -> PathBuf {
+        self.dir.join(format!("{}-{}.txt", name, family.label()))
+    }
+
+    pub fn store(&self, name: &str, family: Family, entries: &[&str]) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Write next to the target and rename, a crash never leaves half a list behind
+        let path = self.path(name, family);
+        let tmp = path.with_extension("tmp");
+        let mut content = entries.join("\n");
+        content.push('\n');
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The cached body together with the time it was written.
+    pub fn load(&self, name: &str, family: Family) -> Result<(String, SystemTime)> {
+        let path = self.path(name, family);
+        let modified = fs::metadata(&path)?.modified()?;
+        Ok((fs::read_to_string(&path)?, modified))
+    }
+}
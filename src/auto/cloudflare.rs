@@ -1,6 +1,7 @@
 use crate::error::{Error, Result};
 use async_trait::async_trait;
 use reqwest::Client;
+use std::time::Duration;
 use super::iplist::IpList;
 
 pub struct CloudflareList {
@@ -29,11 +30,17 @@ impl IpList for CloudflareList {
         format!("ei-{}-ipv6", self.name)
     }
 
+    fn refresh_interval(&self) -> Option<Duration> {
+        // Cloudflare publishes changes well in advance, once a day is plenty
+        Some(Duration::from_secs(24 * 60 * 60))
+    }
+
     async fn fetch_ipv4(&self, client: &Client) -> Result<String> {
         client
             .get("https://www.cloudflare.com/ips-v4")
             .send()
             .await
+            .and_then(reqwest::Response::error_for_status)
             .map_err(|e| Error::CommandFailed(format!("Failed to fetch Cloudflare IPv4: {}", e)))?
             .text()
             .await
@@ -45,6 +52,7 @@ impl IpList for CloudflareList {
             .get("https://www.cloudflare.com/ips-v6")
             .send()
             .await
+            .and_then(reqwest::Response::error_for_status)
             .map_err(|e| Error::CommandFailed(format!("Failed to fetch Cloudflare IPv6: {}", e)))?
             .text()
             .await
@@ -1,11 +1,22 @@
+use super::cache::IpListCache;
+use crate::apply::ApplyQueue;
+use crate::backend::{self, Family, SharedBackend};
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::ipset::IpsetController;
 use crate::rules::IpListConfig;
 use async_trait::async_trait;
+use log::{info, warn};
 use reqwest::Client;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// First retry delay after a failed background refresh, doubled on every further failure.
+const RETRY_BASE: Duration = Duration::from_secs(30);
 
 #[async_trait]
 pub trait IpList: Send + Sync {
@@ -14,6 +25,11 @@ pub trait IpList: Send + Sync {
     fn ipv6_set_name(&self) -> String;
     async fn fetch_ipv4(&self, client: &Client) -> Result<String>;
     async fn fetch_ipv6(&self, client: &Client) -> Result<String>;
+
+    /// How often the list is re-fetched in the background, `None` to never refresh.
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
 }
 
 pub struct ConfigurableIpList {
@@ -41,11 +57,16 @@ impl IpList for ConfigurableIpList {
         format!("ei-{}-ipv6", self.name)
     }
 
+    fn refresh_interval(&self) -> Option<Duration> {
+        self.config.refresh_interval.map(Duration::from_secs)
+    }
+
     async fn fetch_ipv4(&self, client: &Client) -> Result<String> {
         client
             .get(&self.config.urls.ipv4)
             .send()
             .await
+            .and_then(reqwest::Response::error_for_status)
             .map_err(|e| Error::CommandFailed(format!("Failed to fetch IPv4: {}", e)))?
             .text()
             .await
@@ -57,6 +78,7 @@ impl IpList for ConfigurableIpList {
             .get(&self.config.urls.ipv6)
             .send()
             .await
+            .and_then(reqwest::Response::error_for_status)
             .map_err(|e| Error::CommandFailed(format!("Failed to fetch IPv6: {}", e)))?
             .text()
             .await
@@ -64,15 +86,55 @@ impl IpList for ConfigurableIpList {
     }
 }
 
+/// Outcome of the most recent update of one list, shared with the HTTP API.
+#[derive(Debug, Clone, Default)]
+pub struct IpListStatus {
+    /// The live sets hold the on-disk copy because the source was unreachable
+    pub from_cache: bool,
+    /// Unix time the live contents were fetched from the source
+    pub fetched_at: u64,
+    /// Unix time of the most recent update attempt, successful or not
+    pub last_attempt: u64,
+    pub last_duration: Duration,
+    pub last_success: bool,
+    pub last_error: Option<String>,
+}
+
+impl IpListStatus {
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(unix_time(SystemTime::now()).saturating_sub(self.fetched_at))
+    }
+}
+
+pub type SharedListStatus = Arc<RwLock<HashMap<String, IpListStatus>>>;
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Everything a list update touches, cloned into each background refresh task.
+#[derive(Clone)]
+struct UpdateContext {
+    backend: SharedBackend,
+    cache: IpListCache,
+    status: SharedListStatus,
+}
+
 pub struct IpListManager {
-    ipset: Arc<RwLock<IpsetController>>,
-    lists: Vec<Box<dyn IpList>>,
+    ctx: UpdateContext,
+    lists: Vec<Arc<dyn IpList>>,
 }
 
 impl IpListManager {
-    pub fn new(ipset: Arc<RwLock<IpsetController>>) -> Self {
+    pub fn new(backend: SharedBackend, cache: IpListCache, status: SharedListStatus) -> Self {
         IpListManager {
-            ipset,
+            ctx: UpdateContext {
+                backend,
+                cache,
+                status,
+            },
             lists: Vec::new(),
         }
     }
@@ -89,62 +151,307 @@ impl IpListManager {
     }
 
     pub fn add_list(&mut self, list: Box<dyn IpList>) {
-        self.lists.push(list);
+        // A list can be both referenced by a rule and enabled in [iplists]
+        if self.lists.iter().any(|l| l.name() == list.name()) {
+            return;
+        }
+        self.lists.push(Arc::from(list));
     }
 
-    pub async fn update_all(&self) -> Result<()> {
-        let client = Client::builder()
+    /// The members every list set would get from its cached copy, `None` for
+    /// lists that were never cached.
+    pub fn cached_sets(&self) -> Vec<(String, Option<Vec<String>>)> {
+        let mut sets = Vec::new();
+        for list in &self.lists {
+            let cached = self.ctx.load_cached(list.name()).ok();
+            for (set_name, family) in [
+                (list.ipv4_set_name(), Family::V4),
+                (list.ipv6_set_name(), Family::V6),
+            ] {
+                let entries = cached.as_ref().and_then(|(ipv4_ranges, ipv6_ranges, _)| {
+                    let body = match family {
+                        Family::V4 => ipv4_ranges,
+                        Family::V6 => ipv6_ranges,
+                    };
+                    UpdateContext::validate(list.name(), body, family).ok()
+                });
+                sets.push((
+                    set_name,
+                    entries.map(|entries| entries.into_iter().map(str::to_string).collect()),
+                ));
+            }
+        }
+        sets
+    }
+
+    fn client() -> Result<Client> {
+        Client::builder()
             .timeout(Duration::from_secs(10))
             .build()
-            .map_err(|e| Error::CommandFailed(format!("Failed to create HTTP client: {}", e)))?;
+            .map_err(|e| Error::CommandFailed(format!("Failed to create HTTP client: {}", e)))
+    }
+
+    pub async fn update_all(&self) -> Result<()> {
+        let client = Self::client()?;
 
         for list in &self.lists {
-            self.update_list(&client, list.as_ref()).await?;
+            self.ctx.update_list(&client, list.as_ref(), true).await?;
         }
 
         Ok(())
     }
 
-    pub fn register_whitelist_set(&self, name: String) {
-        self.ipset.write().unwrap().register_whitelist_set(name);
+    /// Spawn one background task per list that has a refresh interval. Each
+    /// task only swaps the contents of its own sets, chains are left alone, and
+    /// goes through `queue` so it never interleaves with a reload.
+    pub fn schedule_refresh(&self, queue: &ApplyQueue) -> Result<Vec<JoinHandle<()>>> {
+        let client = Self::client()?;
+
+        Ok(self
+            .lists
+            .iter()
+            .filter_map(|list| {
+                let interval = list.refresh_interval()?;
+                let list = Arc::clone(list);
+                let ctx = self.ctx.clone();
+                let client = client.clone();
+                let queue = queue.clone();
+
+                info!("Refreshing {} every {:?}", list.name(), interval);
+                Some(tokio::spawn(async move {
+                    let mut failures: u32 = 0;
+                    loop {
+                        let delay = if failures == 0 {
+                            interval
+                        } else {
+                            (RETRY_BASE * 2u32.saturating_pow(failures - 1)).min(interval)
+                        };
+                        tokio::time::sleep(Self::jitter(delay)).await;
+
+                        // The live sets already hold the last good copy, so no cache fallback
+                        let update = {
+                            let (ctx, client, list) = (ctx.clone(), client.clone(), list.clone());
+                            async move {
+                                ctx.update_list(&client, list.as_ref(), false)
+                                    .await
+                                    .map_err(|e| e.to_string())
+                            }
+                        };
+                        match queue.run(update).await {
+                            Ok(()) => {
+                                info!("Refreshed IP list {}", list.name());
+                                failures = 0;
+                            }
+                            Err(e) => {
+                                failures = failures.saturating_add(1);
+                                warn!(
+                                    "Failed to refresh IP list {} ({} in a row): {}",
+                                    list.name(),
+                                    failures,
+                                    e
+                                );
+                            }
+                        }
+                    }
+                }))
+            })
+            .collect())
     }
 
-    pub fn register_blacklist_set(&self, name: String) {
-        self.ipset.write().unwrap().register_blacklist_set(name);
+    /// Spread refreshes by up to 10% so lists configured with the same interval
+    /// (or daemons on many hosts) don't all hit the source at once.
+    fn jitter(delay: Duration) -> Duration {
+        let random = RandomState::new().build_hasher().finish();
+        let spread = delay.as_millis() as u64 / 10;
+        if spread == 0 {
+            return delay;
+        }
+        delay + Duration::from_millis(random % spread)
     }
 
-    async fn update_list(&self, client: &Client, list: &dyn IpList) -> Result<()> {
-        let ipv4_set = list.ipv4_set_name();
-        let ipv6_set: String = list.ipv6_set_name();
+    pub async fn register_whitelist_set(&self, name: String) {
+        self.ctx.backend.write().await.register_whitelist_set(name);
+    }
+
+    pub async fn register_blacklist_set(&self, name: String) {
+        self.ctx.backend.write().await.register_blacklist_set(name);
+    }
+}
 
-        // Create/reset IPv4 set
-        self.ipset
-            .write()
-            .unwrap()
-            .create_or_reset_ipset(&ipv4_set)?;
+impl UpdateContext {
+    /// Check that every line of a fetched list is an address or CIDR of the
+    /// expected family, so an error page never replaces a good list.
+    fn validate<'a>(name: &str, body: &'a str, family: Family) -> Result<Vec<&'a str>> {
+        let mut entries = Vec::new();
 
-        // Create/reset IPv6 set
-        self.ipset
-            .write()
-            .unwrap()
-            .create_or_reset_ipset(&ipv6_set)?;
+        for line in body.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
 
-        // Fetch and add IPv4 ranges
-        let ipv4_ranges = list.fetch_ipv4(client).await?;
-        for ip in ipv4_ranges.lines() {
-            if !ip.is_empty() {
-                self.ipset.write().unwrap().add_to_set(&ipv4_set, ip)?;
+            let (addr, prefix) = match line.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (line, None),
+            };
+
+            let max_prefix = match (addr.parse::<IpAddr>(), family) {
+                (Ok(IpAddr::V4(_)), Family::V4) => 32,
+                (Ok(IpAddr::V6(_)), Family::V6) => 128,
+                _ => {
+                    return Err(Error::InvalidList(format!(
+                        "'{}' in {} is not a valid {:?} entry",
+                        line, name, family
+                    )))
+                }
+            };
+
+            if let Some(prefix) = prefix {
+                if !prefix.parse::<u8>().is_ok_and(|p| p <= max_prefix) {
+                    return Err(Error::InvalidList(format!(
+                        "'{}' in {} has an invalid prefix length",
+                        line, name
+                    )));
+                }
             }
+
+            entries.push(line);
         }
 
-        // Fetch and add IPv6 ranges
+        Ok(entries)
+    }
+
+    /// Download both families of a list and make sure they are usable. An
+    /// empty download never replaces a set that has entries.
+    async fn fetch(&self, client: &Client, list: &dyn IpList) -> Result<(String, String)> {
+        let ipv4_ranges = list.fetch_ipv4(client).await?;
         let ipv6_ranges = list.fetch_ipv6(client).await?;
-        for ip in ipv6_ranges.lines() {
-            if !ip.is_empty() {
-                self.ipset.write().unwrap().add_to_set(&ipv6_set, ip)?;
+
+        let ipv4_empty = Self::validate(list.name(), &ipv4_ranges, Family::V4)?.is_empty();
+        let ipv6_empty = Self::validate(list.name(), &ipv6_ranges, Family::V6)?.is_empty();
+
+        if ipv4_empty || ipv6_empty {
+            let sizes = backend::run(&self.backend, |backend| backend.set_sizes()).await?;
+            for (empty, set_name) in [
+                (ipv4_empty, list.ipv4_set_name()),
+                (ipv6_empty, list.ipv6_set_name()),
+            ] {
+                if empty
+                    && sizes
+                        .iter()
+                        .any(|(name, size)| *name == set_name && *size > 0)
+                {
+                    return Err(Error::InvalidList(format!(
+                        "{} came back empty while {} has entries",
+                        list.name(),
+                        set_name
+                    )));
+                }
             }
         }
 
-        Ok(())
+        Ok((ipv4_ranges, ipv6_ranges))
+    }
+
+    fn load_cached(&self, name: &str) -> Result<(String, String, SystemTime)> {
+        let (ipv4_ranges, ipv4_time) = self.cache.load(name, Family::V4)?;
+        let (ipv6_ranges, ipv6_time) = self.cache.load(name, Family::V6)?;
+        Ok((ipv4_ranges, ipv6_ranges, ipv4_time.min(ipv6_time)))
+    }
+
+    async fn update_list(
+        &self,
+        client: &Client,
+        list: &dyn IpList,
+        fallback_to_cache: bool,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let result = self.load_list(client, list, fallback_to_cache).await;
+
+        let mut status = self.status.write().unwrap();
+        let status = status.entry(list.name().to_string()).or_default();
+        status.last_attempt = unix_time(SystemTime::now());
+        status.last_duration = started.elapsed();
+        status.last_success = result.is_ok();
+
+        match result {
+            Ok((from_cache, fetched_at)) => {
+                status.from_cache = from_cache;
+                status.fetched_at = unix_time(fetched_at);
+                status.last_error = None;
+                Ok(())
+            }
+            Err(e) => {
+                status.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Fetch a list (or its cached copy) and swap it into the live sets,
+    /// returning whether the cache was used and when the contents were fetched.
+    async fn load_list(
+        &self,
+        client: &Client,
+        list: &dyn IpList,
+        fallback_to_cache: bool,
+    ) -> Result<(bool, SystemTime)> {
+        let ipv4_set = list.ipv4_set_name();
+        let ipv6_set: String = list.ipv6_set_name();
+
+        // Fetch both families before touching anything, the live sets stay as
+        // they are if either download fails
+        let (ipv4_ranges, ipv6_ranges, from_cache, fetched_at) = match self
+            .fetch(client, list)
+            .await
+        {
+            Ok((ipv4_ranges, ipv6_ranges)) => (ipv4_ranges, ipv6_ranges, false, SystemTime::now()),
+            Err(e) if fallback_to_cache => match self.load_cached(list.name()) {
+                Ok((ipv4_ranges, ipv6_ranges, cached_at)) => {
+                    warn!(
+                        "Failed to fetch IP list {}, loading cached copy from {}s ago: {}",
+                        list.name(),
+                        unix_time(SystemTime::now()).saturating_sub(unix_time(cached_at)),
+                        e
+                    );
+                    (ipv4_ranges, ipv6_ranges, true, cached_at)
+                }
+                Err(_) => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+
+        let ipv4_entries = Self::validate(list.name(), &ipv4_ranges, Family::V4)?;
+        let ipv6_entries = Self::validate(list.name(), &ipv6_ranges, Family::V6)?;
+
+        // Swap in IPv4 and IPv6 ranges
+        let entries: Vec<(String, Family, Vec<String>)> = [
+            (ipv4_set, Family::V4, &ipv4_entries),
+            (ipv6_set, Family::V6, &ipv6_entries),
+        ]
+        .into_iter()
+        .map(|(set, family, entries)| {
+            (set, family, entries.iter().map(|e| e.to_string()).collect())
+        })
+        .collect();
+        backend::run(&self.backend, move |backend| {
+            for (set, family, entries) in &entries {
+                let entries: Vec<&str> = entries.iter().map(String::as_str).collect();
+                backend.replace_set(set, *family, &entries)?;
+            }
+            Ok(())
+        })
+        .await?;
+
+        if !from_cache {
+            if let Err(e) = self
+                .cache
+                .store(list.name(), Family::V4, &ipv4_entries)
+                .and_then(|_| self.cache.store(list.name(), Family::V6, &ipv6_entries))
+            {
+                warn!("Failed to cache IP list {}: {}", list.name(), e);
+            }
+        }
+
+        Ok((from_cache, fetched_at))
     }
 }
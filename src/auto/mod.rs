@@ -1,9 +1,11 @@
 // This is a module to pull remote IP list and allows to resolve into IP rule.
 // Configuring one is not very convenient.
 
+mod cache;
 mod cloudflare;
 mod iplist;
 mod resolver;
 
-pub use iplist::IpListManager;
+pub use cache::IpListCache;
+pub use iplist::{IpListManager, IpListStatus, SharedListStatus};
 pub use resolver::IpListResolver;
@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::config::{BaselineConfig, Config};
+use crate::error::{Error, Result};
+use crate::ipset::IpsetController;
+use crate::iptables::IptablesController;
+use crate::nftables::NftablesController;
+use crate::plan::FirewallState;
+use crate::rules::{PortRange, Protocol, Rule, RuleParser};
+
+/// Which tooling ei uses to program the kernel firewall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Iptables,
+    Nftables,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Family::V4 => "ipv4",
+            Family::V6 => "ipv6",
+        }
+    }
+}
+
+/// Packet and byte counters of one rule in an ei chain.
+#[derive(Debug, Clone)]
+pub struct RuleCounter {
+    /// `ipv4`/`ipv6`, or `inet` for the nftables table that serves both
+    pub family: &'static str,
+    pub chain: String,
+    /// 1-based position of the rule in its chain
+    pub position: usize,
+    pub target: String,
+    /// The rule as printed by the firewall tooling, without its counters
+    pub rule: String,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+/// The ei-owned firewall state as it was before an apply, for `rollback`.
+#[derive(Debug, Clone)]
+pub enum Snapshot {
+    /// The ei lines of `iptables-save` per family, and `ipset save` of the ei sets
+    Iptables {
+        v4: Vec<String>,
+        v6: Vec<String>,
+        sets: String,
+    },
+    /// `nft list table inet ei`, `None` when the table didn't exist
+    Nftables { table: Option<String> },
+}
+
+/// Everything the daemon needs from the firewall: the ei chains and the sets they match against.
+pub trait FirewallBackend: Send + Sync {
+    /// Create the main `ei` chain and hook it into the input and forward paths.
+    /// Until the first apply it only accepts established connections, if
+    /// `baseline` asks for that.
+    fn init(&self, baseline: &BaselineConfig) -> Result<()>;
+
+    /// Create the port sets that don't exist yet, an apply fills them.
+    fn init_sets(&self) -> Result<()>;
+
+    /// Render the whitelist, blacklist and feature chains from the config.
+    fn configure_with_rules(&self, config: &Config, rule_parser: &RuleParser) -> Result<()>;
+
+    fn configure_port_rules(&self, rules: &[&Rule]) -> Result<()>;
+
+    /// Atomically replace the members of a list set, creating it if needed.
+    fn replace_set(&self, set_name: &str, family: Family, values: &[&str]) -> Result<()>;
+
+    fn add_to_set(&self, set_name: &str, value: &str) -> Result<()>;
+
+    /// The allowed ports, consecutive ones reported as ranges.
+    fn list_ports(&self) -> Result<Vec<(PortRange, Protocol)>>;
+
+    /// Whether `add_port` and `remove_port` take `protocol`, an
+    /// `Error::Unsupported` saying why not otherwise.
+    fn check_runtime_port(&self, _protocol: Protocol) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_port(&self, port: u16, protocol: Protocol) -> Result<()>;
+
+    fn remove_port(&self, port: u16, protocol: Protocol) -> Result<()>;
+
+    /// Counters of every rule in the ei chains.
+    fn rule_counters(&self) -> Result<Vec<RuleCounter>>;
+
+    /// Number of members of every ei set.
+    fn set_sizes(&self) -> Result<Vec<(String, usize)>>;
+
+    /// Remove every chain, hook and set ei created, for both families.
+    fn teardown(&self) -> Result<()>;
+
+    /// The ei-owned rules and set members as they are installed right now.
+    fn current_state(&self) -> Result<FirewallState>;
+
+    /// What applying the config would install, except the members of list sets.
+    fn desired_state(&self, config: &Config, rule_parser: &RuleParser) -> Result<FirewallState>;
+
+    /// Capture the ei chains, hooks and sets so a failed apply can be undone.
+    fn snapshot(&self) -> Result<Snapshot>;
+
+    /// Put back what `snapshot` captured.
+    fn rollback(&self, snapshot: &Snapshot) -> Result<()>;
+
+    fn register_whitelist_set(&mut self, name: String);
+
+    fn register_blacklist_set(&mut self, name: String);
+}
+
+pub type SharedBackend = Arc<RwLock<Box<dyn FirewallBackend>>>;
+
+/// Call into the backend on the blocking pool, the commands it runs can take a
+/// while and must not stall the runtime.
+pub async fn run<T, F>(backend: &SharedBackend, f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn FirewallBackend) -> Result<T> + Send + 'static,
+{
+    let backend = backend.clone();
+    tokio::task::spawn_blocking(move || f(backend.blocking_read().as_ref()))
+        .await
+        .map_err(|e| Error::CommandFailed(format!("Backend task failed: {}", e)))?
+}
+
+pub fn new_backend(backend: Backend, dry_run: bool) -> Box<dyn FirewallBackend> {
+    match backend {
+        Backend::Iptables => Box::new(IptablesBackend::new(dry_run)),
+        Backend::Nftables => Box::new(NftablesController::new(dry_run)),
+    }
+}
+
+/// The legacy xtables path: chains through `iptables`/`ip6tables`, sets through `ipset`.
+pub struct IptablesBackend {
+    iptables: IptablesController,
+    ipset: IpsetController,
+}
+
+impl IptablesBackend {
+    pub fn new(dry_run: bool) -> Self {
+        IptablesBackend {
+            iptables: IptablesController::new(dry_run),
+            ipset: IpsetController::new(dry_run),
+        }
+    }
+
+    /// Run every command through `executor` instead of spawning processes.
+    #[cfg(test)]
+    pub fn with_executor(executor: Arc<dyn crate::cmd::Executor>) -> Self {
+        IptablesBackend {
+            iptables: IptablesController::with_executor(false, executor.clone()),
+            ipset: IpsetController::with_executor(false, executor),
+        }
+    }
+
+    /// Read SSH sessions from `proc_root` instead of `/proc`.
+    #[cfg(test)]
+    pub fn with_proc_root(mut self, proc_root: std::path::PathBuf) -> Self {
+        self.iptables = self.iptables.with_proc_root(proc_root);
+        self
+    }
+}
+
+impl FirewallBackend for IptablesBackend {
+    fn init(&self, baseline: &BaselineConfig) -> Result<()> {
+        self.iptables.init(baseline)
+    }
+
+    fn init_sets(&self) -> Result<()> {
+        self.ipset.init()
+    }
+
+    fn configure_with_rules(&self, config: &Config, rule_parser: &RuleParser) -> Result<()> {
+        self.iptables
+            .configure_with_rules(config, rule_parser, &self.ipset)
+    }
+
+    fn configure_port_rules(&self, rules: &[&Rule]) -> Result<()> {
+        self.ipset.configure_port_rules(rules)
+    }
+
+    fn replace_set(&self, set_name: &str, family: Family, values: &[&str]) -> Result<()> {
+        self.ipset.replace_set(set_name, family, values)
+    }
+
+    fn add_to_set(&self, set_name: &str, value: &str) -> Result<()> {
+        self.ipset.add_to_set(set_name, value)
+    }
+
+    fn list_ports(&self) -> Result<Vec<(PortRange, Protocol)>> {
+        self.ipset.list_ports()
+    }
+
+    fn check_runtime_port(&self, protocol: Protocol) -> Result<()> {
+        IpsetController::check_runtime_port(protocol)
+    }
+
+    fn add_port(&self, port: u16, protocol: Protocol) -> Result<()> {
+        self.ipset.add_port(port, protocol)
+    }
+
+    fn remove_port(&self, port: u16, protocol: Protocol) -> Result<()> {
+        self.ipset.remove_port(port, protocol)
+    }
+
+    fn rule_counters(&self) -> Result<Vec<RuleCounter>> {
+        self.iptables.rule_counters()
+    }
+
+    fn set_sizes(&self) -> Result<Vec<(String, usize)>> {
+        self.ipset.set_sizes()
+    }
+
+    fn teardown(&self) -> Result<()> {
+        self.iptables.teardown()?;
+        self.ipset.teardown()
+    }
+
+    fn current_state(&self) -> Result<FirewallState> {
+        let mut state = FirewallState::default();
+        self.iptables.current_rules(&mut state)?;
+        self.ipset.current_sets(&mut state)?;
+        Ok(state)
+    }
+
+    fn desired_state(&self, config: &Config, rule_parser: &RuleParser) -> Result<FirewallState> {
+        let mut state = FirewallState::default();
+        self.iptables
+            .desired_rules(&mut state, config, rule_parser, &self.ipset);
+        self.ipset.desired_sets(&mut state, rule_parser);
+        Ok(state)
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::Iptables {
+            v4: self.iptables.save(Family::V4)?,
+            v6: self.iptables.save(Family::V6)?,
+            sets: self.ipset.snapshot()?,
+        })
+    }
+
+    fn rollback(&self, snapshot: &Snapshot) -> Result<()> {
+        let Snapshot::Iptables { v4, v6, sets } = snapshot else {
+            return Err(Error::CommandFailed(
+                "snapshot was not taken by the iptables backend".to_string(),
+            ));
+        };
+        // Sets first, the restored rules may match on them
+        self.ipset.rollback(sets)?;
+        self.iptables.rollback(Family::V4, v4)?;
+        self.iptables.rollback(Family::V6, v6)
+    }
+
+    fn register_whitelist_set(&mut self, name: String) {
+        self.ipset.register_whitelist_set(name)
+    }
+
+    fn register_blacklist_set(&mut self, name: String) {
+        self.ipset.register_blacklist_set(name)
+    }
+}
@@ -1,7 +1,8 @@
 use clap::{Parser, Subcommand};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-// use std::path::PathBuf;
+use std::env;
+use std::path::PathBuf;
 use std::process::exit;
 
 #[derive(Parser)]
@@ -26,7 +27,7 @@ enum Commands {
         #[arg(value_parser = port_in_range)]
         port: u16,
 
-        /// Protocol (tcp/udp)
+        /// Protocol (tcp/udp/sctp, or any for tcp+udp)
         #[arg(value_parser = parse_protocol)]
         protocol: String,
     },
@@ -37,7 +38,7 @@ enum Commands {
         #[arg(value_parser = port_in_range)]
         port: u16,
 
-        /// Protocol (tcp/udp)
+        /// Protocol (tcp/udp/sctp, or any for tcp+udp)
         #[arg(value_parser = parse_protocol)]
         protocol: String,
     },
@@ -47,6 +48,21 @@ enum Commands {
 
     /// Reload configuration
     Reload,
+
+    /// Apply the configuration, optionally reverting it unless confirmed in time
+    Apply {
+        /// Revert unless `ei confirm` runs within this long (e.g. 60s, 2m)
+        #[arg(long, value_parser = parse_duration)]
+        confirm_within: Option<u64>,
+    },
+
+    /// Keep the configuration applied with `--confirm-within`
+    Confirm,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ApplyRequest {
+    confirm_within: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -65,42 +81,65 @@ fn port_in_range(s: &str) -> Result<u16, String> {
 
 fn parse_protocol(s: &str) -> Result<String, String> {
     let protocol = s.to_lowercase();
-    if protocol != "tcp" && protocol != "udp" {
-        return Err("Protocol must be either 'tcp' or 'udp'".to_string());
+    match protocol.as_str() {
+        "tcp" | "udp" | "sctp" => Ok(protocol),
+        // Both TCP and UDP
+        "any" | "tcp+udp" | "udp+tcp" => Ok("any".to_string()),
+        _ => Err("Protocol must be 'tcp', 'udp', 'sctp' or 'any' (tcp+udp)".to_string()),
+    }
+}
+
+/// Seconds in a duration such as `90`, `60s`, `2m` or `1h`.
+fn parse_duration(s: &str) -> Result<u64, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let value: u64 = number
+        .parse()
+        .map_err(|_| "Duration must be a number with an optional s/m/h suffix")?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 3600,
+        _ => return Err(format!("Unknown duration unit '{}', use s, m or h", unit)),
+    };
+    if secs == 0 {
+        return Err("Duration cannot be 0".to_string());
     }
-    Ok(protocol)
+    Ok(secs)
 }
 
-// #[cfg(unix)]
-// fn find_config() -> Option<PathBuf> {
-//     // Check current directory first
-//     let current_dir = env::current_dir().ok()?;
-//     let local_config = current_dir.join("config.toml");
-//     if local_config.exists() {
-//         return Some(local_config);
-//     }
-
-//     // Check user config directory
-//     if let Some(user_config_dir) = dirs::config_dir() {
-//         let user_config = user_config_dir.join("ei/config.toml");
-//         if user_config.exists() {
-//             return Some(user_config);
-//         }
-//     }
-
-//     // Check system-wide config
-//     let system_config = PathBuf::from("/etc/ei/config.toml");
-//     if system_config.exists() {
-//         return Some(system_config);
-//     }
-
-//     None
-// }
-
-// #[cfg(windows)]
-// fn find_config() -> Option<PathBuf> {
-//     None
-// }
+#[cfg(unix)]
+fn find_config() -> Option<PathBuf> {
+    // Check current directory first
+    let current_dir = env::current_dir().ok()?;
+    let local_config = current_dir.join("config.toml");
+    if local_config.exists() {
+        return Some(local_config);
+    }
+
+    // Check user config directory
+    if let Some(user_config_dir) = dirs::config_dir() {
+        let user_config = user_config_dir.join("ei/config.toml");
+        if user_config.exists() {
+            return Some(user_config);
+        }
+    }
+
+    // Check system-wide config
+    let system_config = PathBuf::from("/etc/ei/config.toml");
+    if system_config.exists() {
+        return Some(system_config);
+    }
+
+    None
+}
+
+#[cfg(windows)]
+fn find_config() -> Option<PathBuf> {
+    None
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ClientConfig {
@@ -189,12 +228,30 @@ async fn main() {
                 }
             }
         }
+        Commands::Apply { confirm_within } => {
+            match apply_config(&client, &endpoint, confirm_within).await {
+                Ok(response) => println!("{}", response),
+                Err(e) => {
+                    eprintln!("Failed to apply configuration: {}", e);
+                    exit(1);
+                }
+            }
+        }
+        Commands::Confirm => {
+            match confirm_apply(&client, &endpoint).await {
+                Ok(response) => println!("{}", response),
+                Err(e) => {
+                    eprintln!("Failed to confirm configuration: {}", e);
+                    exit(1);
+                }
+            }
+        }
     }
 }
 
 async fn list_ports(client: &Client, endpoint: &str) -> Result<String, reqwest::Error> {
     let response = client
-        .get(&format!("{}/ports", endpoint))
+        .get(format!("{}/ports", endpoint))
         .send()
         .await?
         .text()
@@ -207,20 +264,19 @@ async fn add_port(
     endpoint: &str,
     port: u16,
     protocol: &str,
-) -> Result<String, reqwest::Error> {
+) -> Result<String, String> {
     let port_data = Port {
         number: port,
         protocol: protocol.to_string(),
     };
 
     let response = client
-        .put(&format!("{}/ports", endpoint))
+        .put(format!("{}/ports", endpoint))
         .json(&port_data)
         .send()
-        .await?
-        .text()
-        .await?;
-    Ok(response)
+        .await
+        .map_err(|e| e.to_string())?;
+    checked_text(response).await
 }
 
 async fn remove_port(
@@ -228,25 +284,24 @@ async fn remove_port(
     endpoint: &str,
     port: u16,
     protocol: &str,
-) -> Result<String, reqwest::Error> {
+) -> Result<String, String> {
     let port_data = Port {
         number: port,
         protocol: protocol.to_string(),
     };
 
     let response = client
-        .delete(&format!("{}/ports", endpoint))
+        .delete(format!("{}/ports", endpoint))
         .json(&port_data)
         .send()
-        .await?
-        .text()
-        .await?;
-    Ok(response)
+        .await
+        .map_err(|e| e.to_string())?;
+    checked_text(response).await
 }
 
 async fn get_metrics(client: &Client, endpoint: &str) -> Result<String, reqwest::Error> {
     let response = client
-        .get(&format!("{}/metrics", endpoint))
+        .get(format!("{}/metrics", endpoint))
         .send()
         .await?
         .text()
@@ -256,10 +311,45 @@ async fn get_metrics(client: &Client, endpoint: &str) -> Result<String, reqwest:
 
 async fn reload_config(client: &Client, endpoint: &str) -> Result<String, reqwest::Error> {
     let response = client
-        .post(&format!("{}/reload", endpoint))
+        .post(format!("{}/reload", endpoint))
         .send()
         .await?
         .text()
         .await?;
     Ok(response)
+}
+
+/// The body of a response, or an error carrying it when the status isn't a success,
+/// so a failed apply or confirm exits non-zero.
+async fn checked_text(response: reqwest::Response) -> Result<String, String> {
+    let status = response.status();
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(format!("{} ({})", body.trim(), status))
+    }
+}
+
+async fn apply_config(
+    client: &Client,
+    endpoint: &str,
+    confirm_within: Option<u64>,
+) -> Result<String, String> {
+    let response = client
+        .post(format!("{}/apply", endpoint))
+        .json(&ApplyRequest { confirm_within })
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    checked_text(response).await
+}
+
+async fn confirm_apply(client: &Client, endpoint: &str) -> Result<String, String> {
+    let response = client
+        .post(format!("{}/confirm", endpoint))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    checked_text(response).await
 }
\ No newline at end of file
@@ -1,12 +1,61 @@
 use crate::error::{Error, Result};
 use log::{debug, warn};
-use std::process::Command;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+/// Runs the commands a `CmdBuilder` describes.
+pub trait Executor: Send + Sync {
+    /// Run `program` with `args`, feeding `input` on stdin, and return its stdout.
+    fn execute(&self, program: &str, args: &[String], input: Option<&str>) -> Result<String>;
+}
+
+/// Spawns real processes.
+pub struct SystemExecutor;
+
+impl Executor for SystemExecutor {
+    fn execute(&self, program: &str, args: &[String], input: Option<&str>) -> Result<String> {
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::CommandFailed(e.to_string()))?;
+
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            if let Err(e) = stdin.write_all(input.as_bytes()) {
+                // Reap the child so a failed write doesn't leave a zombie behind
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e.into());
+            }
+        }
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| Error::CommandFailed(e.to_string()))?;
+
+        if !output.status.success() {
+            return Err(Error::CommandFailed(
+                String::from_utf8_lossy(&output.stderr).into_owned(),
+            ));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
 
 #[derive(Clone)]
 pub struct CmdBuilder {
     program: String,
     args: Vec<String>,
     dry_run: bool,
+    executor: Arc<dyn Executor>,
 }
 
 impl CmdBuilder {
@@ -16,6 +65,7 @@ impl CmdBuilder {
             program: program.to_string(),
             args: Vec::new(),
             dry_run: false,
+            executor: Arc::new(SystemExecutor),
         }
     }
 
@@ -25,6 +75,12 @@ impl CmdBuilder {
         self
     }
 
+    #[inline]
+    pub fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
     #[inline]
     pub fn arg<S: AsRef<str>>(&mut self, arg: S) -> &mut Self {
         self.args.push(arg.as_ref().to_string());
@@ -50,18 +106,69 @@ impl CmdBuilder {
         if self.dry_run {
             Ok(String::new())
         } else {
-            let output = Command::new(&self.program)
-                .args(&self.args)
-                .output()
-                .map_err(|e| Error::CommandFailed(e.to_string()))?;
-
-            if !output.status.success() {
-                return Err(Error::CommandFailed(
-                    String::from_utf8_lossy(&output.stderr).into_owned(),
-                ));
-            }
+            self.executor.execute(&self.program, &self.args, None)
+        }
+    }
+
+    pub fn execute_with_input(&self, input: &str) -> Result<String> {
+        let cmd_str = format!("{} {}", self.program, self.args.join(" "));
+        debug!("Executing: {} <<EOF\n{}EOF", cmd_str, input);
+
+        if self.dry_run {
+            return Ok(String::new());
+        }
+
+        self.executor
+            .execute(&self.program, &self.args, Some(input))
+    }
+}
+
+/// Records every command instead of running it, answering with scripted output.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingExecutor {
+    calls: std::sync::Mutex<Vec<String>>,
+    responses: std::sync::Mutex<std::collections::HashMap<String, (bool, String)>>,
+}
+
+#[cfg(test)]
+impl RecordingExecutor {
+    /// Answer `command` (program and arguments, space separated) with `stdout`.
+    /// Commands without a scripted answer succeed with empty output.
+    pub fn respond(&self, command: &str, stdout: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(command.to_string(), (true, stdout.to_string()));
+    }
+
+    /// Make `command` fail with `stderr`.
+    pub fn fail(&self, command: &str, stderr: &str) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(command.to_string(), (false, stderr.to_string()));
+    }
+
+    /// Every command so far, with its stdin as a heredoc.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Executor for RecordingExecutor {
+    fn execute(&self, program: &str, args: &[String], input: Option<&str>) -> Result<String> {
+        let command = format!("{} {}", program, args.join(" "));
+        self.calls.lock().unwrap().push(match input {
+            Some(input) => format!("{} <<EOF\n{}EOF", command, input),
+            None => command.clone(),
+        });
 
-            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        match self.responses.lock().unwrap().get(&command) {
+            Some((true, stdout)) => Ok(stdout.clone()),
+            Some((false, stderr)) => Err(Error::CommandFailed(stderr.clone())),
+            None => Ok(String::new()),
         }
     }
 }
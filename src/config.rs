@@ -2,12 +2,16 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-use crate::rules::Rule;
+use crate::auto::IpListResolver;
+use crate::backend::Backend;
+use crate::rules::{PortFromRule, Rule, Source};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
+    #[serde(default)]
+    pub backend: Backend,
     pub server: ServerConfig,
     pub features: FeaturesConfig,
     pub whitelist: AccessListConfig,
@@ -15,9 +19,19 @@ pub struct Config {
     pub docker: bool,
     pub interfaces: Vec<String>,
     pub iplists: HashMap<String, crate::IpListConfig>,
+    /// Where runtime state such as cached IP lists is kept, defaults to /var/lib/ei
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<PathBuf>,
+    /// Remove all ei chains and sets when the daemon stops
+    #[serde(default)]
+    pub teardown_on_stop: bool,
+    #[serde(default)]
+    pub ssh_guard: SshGuardConfig,
+    #[serde(default)]
+    pub baseline: BaselineConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
@@ -32,13 +46,71 @@ impl Default for ServerConfig {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Keeps the SSH sessions open at apply time reachable, whatever the lists say.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SshGuardConfig {
+    pub enabled: bool,
+}
+
+impl Default for SshGuardConfig {
+    fn default() -> Self {
+        SshGuardConfig { enabled: true }
+    }
+}
+
+/// What the end of the `ei` chain does with traffic nothing else matched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinalAction {
+    Drop,
+    Reject,
+    /// Leave it to the policy of INPUT and FORWARD
+    #[default]
+    Return,
+}
+
+impl FinalAction {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FinalAction::Drop => "drop",
+            FinalAction::Reject => "reject",
+            FinalAction::Return => "return",
+        }
+    }
+}
+
+/// The stateful rules around everything else in `ei`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BaselineConfig {
+    /// Accept packets of connections conntrack already tracks before any other rule
+    pub accept_established: bool,
+    pub final_action: FinalAction,
+    /// Interfaces the final action applies to, every one but loopback when empty
+    pub wan_interfaces: Vec<String>,
+}
+
+impl Default for BaselineConfig {
+    fn default() -> Self {
+        BaselineConfig {
+            accept_established: true,
+            final_action: FinalAction::default(),
+            wan_interfaces: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct FeaturesConfig {
     pub portforward: bool,
     pub block_badtcp: bool,
+    /// Also drop new TCP connections announcing an MSS no real stack would use
+    #[serde(default)]
+    pub badtcp_check_mss: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct AccessListConfig {
     pub enabled: bool,
     pub rules: Vec<Rule>,
@@ -53,7 +125,36 @@ impl Config {
         }
 
         let content = fs::read_to_string(path)?;
-        toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        let config: Config =
+            toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config.check_source_lists()?;
+        Ok(config)
+    }
+
+    /// Compound rules can only match on lists that get fetched, a missing set
+    /// would fail the whole apply.
+    fn check_source_lists(&self) -> io::Result<()> {
+        let mut resolver = IpListResolver::new();
+        resolver.load_config(self);
+
+        for rule in self.whitelist.rules.iter().chain(&self.blacklist.rules) {
+            if let Rule::PortFrom(PortFromRule {
+                source: Source::IpList(list),
+                ..
+            }) = rule
+            {
+                if resolver.resolve(&Rule::IpList(list.clone())).is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "IP list '{}' used as a rule source is not defined or not enabled in [iplists]",
+                            list.name
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn save(&self, path: &Path) -> io::Result<()> {
@@ -83,6 +184,16 @@ impl Config {
         }
     }
 
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("/var/lib/ei"))
+    }
+
+    pub fn pidfile(&self) -> PathBuf {
+        self.state_dir().join("eidamon.pid")
+    }
+
     // pub fn get_iplist_config(&self, name: &str) -> Option<&crate::IpListConfig> {
     //     self.iplists.get(name)
     // }
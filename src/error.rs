@@ -7,12 +7,20 @@ pub enum Error {
 
     // #[error("Invalid protocol: {0}")]
     // InvalidProtocol(String),
-
     #[error("Command failed: {0}")]
     CommandFailed(String),
 
+    #[error("Invalid IP list: {0}")]
+    InvalidList(String),
+
+    /// A request the backend can't carry out, the caller's to fix
+    #[error("{0}")]
+    Unsupported(String),
+
+    #[error("{cause} ({rollback})")]
+    ApplyFailed { cause: Box<Error>, rollback: String },
     // #[error("Configuration error: {0}")]
     // Config(String),
 }
 
-pub type Result<T> = std::result::Result<T, Error>; 
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,156 @@
+use log::{debug, warn};
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+use crate::backend::Family;
+
+/// Where sshd listens when its sockets can't be found in `/proc`.
+const DEFAULT_SSH_PORT: u16 = 22;
+
+/// `st` of a socket in `/proc/net/tcp*`.
+const TCP_ESTABLISHED: &str = "01";
+const TCP_LISTEN: &str = "0A";
+
+/// An inbound connection to sshd, as seen from this host.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SshSession {
+    pub remote: IpAddr,
+    pub remote_port: u16,
+    pub local_port: u16,
+}
+
+impl SshSession {
+    pub fn family(&self) -> Family {
+        match self.remote {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+/// One line of `/proc/net/tcp` or `/proc/net/tcp6`.
+struct Socket {
+    local: (IpAddr, u16),
+    remote: (IpAddr, u16),
+    state: String,
+    inode: u64,
+}
+
+impl Socket {
+    fn parse(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        Some(Socket {
+            local: parse_endpoint(fields.get(1)?)?,
+            remote: parse_endpoint(fields.get(2)?)?,
+            state: fields.get(3)?.to_string(),
+            inode: fields.get(9)?.parse().ok()?,
+        })
+    }
+}
+
+/// `0100007F:0016` into 127.0.0.1 port 22. The kernel prints every 32-bit
+/// word of the address in host byte order.
+fn parse_endpoint(field: &str) -> Option<(IpAddr, u16)> {
+    let (addr, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut bytes = Vec::with_capacity(16);
+    for word in (0..addr.len()).step_by(8) {
+        let word = u32::from_str_radix(addr.get(word..word + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+
+    let addr = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => {
+            let addr = Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?);
+            // Dual-stack sockets report IPv4 peers as ::ffff:a.b.c.d
+            match addr.to_ipv4_mapped() {
+                Some(v4) => IpAddr::V4(v4),
+                None => IpAddr::V6(addr),
+            }
+        }
+        _ => return None,
+    };
+    Some((addr, port))
+}
+
+fn read_sockets(proc_root: &Path) -> Vec<Socket> {
+    let mut sockets = Vec::new();
+    for file in ["net/tcp", "net/tcp6"] {
+        match fs::read_to_string(proc_root.join(file)) {
+            // The first line is the column header
+            Ok(content) => sockets.extend(content.lines().skip(1).filter_map(Socket::parse)),
+            Err(e) => debug!("Could not read {}: {}", proc_root.join(file).display(), e),
+        }
+    }
+    sockets
+}
+
+/// Socket inodes held open by processes named `sshd`.
+fn sshd_inodes(proc_root: &Path) -> HashSet<u64> {
+    let mut inodes = HashSet::new();
+    let Ok(entries) = fs::read_dir(proc_root) else {
+        return inodes;
+    };
+
+    for entry in entries.flatten() {
+        let pid_dir = entry.path();
+        let is_sshd =
+            fs::read_to_string(pid_dir.join("comm")).is_ok_and(|comm| comm.trim() == "sshd");
+        if !is_sshd {
+            continue;
+        }
+
+        let Ok(fds) = fs::read_dir(pid_dir.join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let inode = target
+                .to_str()
+                .and_then(|t| t.strip_prefix("socket:["))
+                .and_then(|t| t.strip_suffix(']'))
+                .and_then(|t| t.parse().ok());
+            if let Some(inode) = inode {
+                inodes.insert(inode);
+            }
+        }
+    }
+    inodes
+}
+
+/// Established inbound connections to the ports sshd listens on, read from
+/// `proc_root` (normally `/proc`).
+pub fn ssh_sessions(proc_root: &Path) -> Vec<SshSession> {
+    let sockets = read_sockets(proc_root);
+    let inodes = sshd_inodes(proc_root);
+
+    let mut ports: BTreeSet<u16> = sockets
+        .iter()
+        .filter(|socket| socket.state == TCP_LISTEN && inodes.contains(&socket.inode))
+        .map(|socket| socket.local.1)
+        .collect();
+    if ports.is_empty() {
+        warn!(
+            "Could not find the ports sshd listens on, guarding port {}",
+            DEFAULT_SSH_PORT
+        );
+        ports.insert(DEFAULT_SSH_PORT);
+    }
+
+    let sessions: BTreeSet<SshSession> = sockets
+        .iter()
+        .filter(|socket| socket.state == TCP_ESTABLISHED && ports.contains(&socket.local.1))
+        .map(|socket| SshSession {
+            remote: socket.remote.0,
+            remote_port: socket.remote.1,
+            local_port: socket.local.1,
+        })
+        .collect();
+    sessions.into_iter().collect()
+}
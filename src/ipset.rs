@@ -1,9 +1,63 @@
-use log::info;
+use log::{info, warn};
 
-use crate::cmd::CmdBuilder;
-use crate::error::Result;
-use crate::rules::{PortRule, Protocol, Rule};
-use std::collections::HashSet;
+use crate::backend::Family;
+use crate::cmd::{CmdBuilder, Executor, SystemExecutor};
+use crate::error::{Error, Result};
+use crate::plan::FirewallState;
+use crate::rules::{PortRange, PortRule, Protocol, Rule, RuleParser};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Smallest maxelem for list sets, bigger lists get the next power of two of their size.
+const MIN_MAXELEM: usize = 65536;
+
+/// The parts of `ipset list -t` ei cares about.
+#[derive(Debug, Clone)]
+pub struct SetHeader {
+    pub set_type: String,
+    pub family: Option<String>,
+}
+
+impl SetHeader {
+    fn parse(output: &str) -> Option<Self> {
+        let mut set_type = None;
+        let mut family = None;
+
+        for line in output.lines() {
+            if let Some(value) = line.strip_prefix("Type: ") {
+                set_type = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("Header: ") {
+                family = value
+                    .split_whitespace()
+                    .skip_while(|word| *word != "family")
+                    .nth(1)
+                    .map(str::to_string);
+            }
+        }
+
+        Some(SetHeader {
+            set_type: set_type?,
+            family,
+        })
+    }
+
+    fn is_list_set(&self, family: &str) -> bool {
+        self.set_type == "hash:net" && self.family.as_deref() == Some(family)
+    }
+
+    /// The families of the source addresses the set can match, empty for types
+    /// like `bitmap:port` or `hash:ip,port` that need more than the source
+    /// address. A `list:set` can hold sets of either family.
+    fn source_families(&self) -> Vec<Family> {
+        match (self.set_type.as_str(), self.family.as_deref()) {
+            ("list:set", _) => vec![Family::V4, Family::V6],
+            ("bitmap:ip", _) => vec![Family::V4],
+            ("hash:ip" | "hash:net", Some("inet")) => vec![Family::V4],
+            ("hash:ip" | "hash:net", Some("inet6")) => vec![Family::V6],
+            _ => Vec::new(),
+        }
+    }
+}
 
 pub struct IpsetController {
     cmd: CmdBuilder,
@@ -12,9 +66,19 @@ pub struct IpsetController {
 }
 
 impl IpsetController {
+    /// The protocols `bitmap:port` sets can match, the kernel only extracts TCP
+    /// and UDP ports for them. SCTP ports are matched with multiport rules.
+    pub const SET_PROTOCOLS: [Protocol; 2] = [Protocol::TCP, Protocol::UDP];
+
     pub fn new(dry_run: bool) -> Self {
+        Self::with_executor(dry_run, Arc::new(SystemExecutor))
+    }
+
+    pub fn with_executor(dry_run: bool, executor: Arc<dyn Executor>) -> Self {
         IpsetController {
-            cmd: CmdBuilder::new("ipset").with_dry_run(dry_run),
+            cmd: CmdBuilder::new("ipset")
+                .with_dry_run(dry_run)
+                .with_executor(executor),
             whitelist_sets: HashSet::new(),
             blacklist_sets: HashSet::new(),
         }
@@ -22,39 +86,183 @@ impl IpsetController {
 
     pub fn init(&self) -> Result<()> {
         info!("Initializing ipset controller");
-        self.create_or_reset_port_set("ei-allowed-tcp-ports")?;
-        self.create_or_reset_port_set("ei-allowed-udp-ports")?;
-        self.create_or_reset_port_set("ei-whitelist-tcp")?;
-        self.create_or_reset_port_set("ei-whitelist-udp")?;
-        self.create_or_reset_port_set("ei-blacklist-tcp")?;
-        self.create_or_reset_port_set("ei-blacklist-udp")?;
+        for set_name in Self::port_set_names() {
+            self.create_port_set(&set_name)?;
+        }
         Ok(())
     }
 
-    pub fn add_to_whitelist(&self, port: u16, protocol: Protocol) -> Result<()> {
-        let set_name = match protocol {
-            Protocol::TCP => "ei-whitelist-tcp",
-            Protocol::UDP => "ei-whitelist-udp",
-        };
-        self.add_to_set(set_name, &port.to_string())
+    /// The set of ports opened by port rules and `ei add`, e.g. `ei-allowed-tcp-ports`.
+    pub fn allowed_set_name(protocol: Protocol) -> String {
+        format!("ei-allowed-{}-ports", protocol.to_string())
     }
 
-    pub fn add_to_blacklist(&self, port: u16, protocol: Protocol) -> Result<()> {
-        let set_name = match protocol {
-            Protocol::TCP => "ei-blacklist-tcp",
-            Protocol::UDP => "ei-blacklist-udp",
-        };
-        self.add_to_set(set_name, &port.to_string())
+    /// The set of ports of the whitelist or blacklist, e.g. `ei-whitelist-udp`.
+    pub fn list_port_set_name(list: &str, protocol: Protocol) -> String {
+        format!("ei-{}-{}", list, protocol.to_string())
+    }
+
+    /// Every port set, one per list and protocol.
+    pub fn port_set_names() -> Vec<String> {
+        let allowed = Self::SET_PROTOCOLS.map(Self::allowed_set_name);
+        let lists = ["whitelist", "blacklist"].into_iter().flat_map(|list| {
+            Self::SET_PROTOCOLS.map(|protocol| Self::list_port_set_name(list, protocol))
+        });
+        allowed.into_iter().chain(lists).collect()
+    }
+
+    /// The protocols of `protocol` that have a port set.
+    fn set_protocols(protocol: Protocol) -> impl Iterator<Item = Protocol> {
+        protocol
+            .expand()
+            .iter()
+            .copied()
+            .filter(|protocol| Self::SET_PROTOCOLS.contains(protocol))
+    }
+
+    /// Refill the port sets of the whitelist or blacklist from its rules.
+    pub fn replace_list_port_sets(&self, list: &str, rules: &[Rule]) -> Result<()> {
+        for protocol in Self::SET_PROTOCOLS {
+            self.replace_port_set(
+                &Self::list_port_set_name(list, protocol),
+                &Self::set_ports(rules.iter(), protocol),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The ports of the port rules covering `protocol`, as `bitmap:port`
+    /// members; a range goes in as a single `first-last` entry.
+    fn set_ports<'a>(rules: impl Iterator<Item = &'a Rule>, protocol: Protocol) -> Vec<String> {
+        rules
+            .filter_map(|rule| match rule {
+                Rule::Port(PortRule {
+                    ports,
+                    protocol: rule_protocol,
+                }) if Self::set_protocols(*rule_protocol).any(|p| p == protocol) => {
+                    Some(ports.to_string())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `hash:net` set holding the `ip:` rules of the whitelist or blacklist.
+    pub fn net_set_name(list: &str, family: Family) -> String {
+        format!("ei-{}-net-{}", list, family.label())
+    }
+
+    fn family_name(family: Family) -> &'static str {
+        match family {
+            Family::V4 => "inet",
+            Family::V6 => "inet6",
+        }
     }
 
-    pub fn create_or_reset_ipset(&self, set_name: &str) -> Result<()> {
+    pub fn create_or_reset_ipset(
+        &self,
+        set_name: &str,
+        family: Family,
+        maxelem: usize,
+    ) -> Result<()> {
         let _ = self.execute(&["destroy", set_name]);
         self.execute(&[
-            "create", set_name, "hash:ip", "family", "inet", "maxelem", "65536",
+            "create",
+            set_name,
+            "hash:net",
+            "family",
+            Self::family_name(family),
+            "maxelem",
+            &maxelem.to_string(),
         ])?;
         Ok(())
     }
 
+    /// Read the header of a set, `None` if it doesn't exist.
+    pub fn set_header(&self, set_name: &str) -> Option<SetHeader> {
+        let output = self.execute(&["list", "-t", set_name]).ok()?;
+        SetHeader::parse(&output)
+    }
+
+    /// The externally managed sets of `ipset:` rules, with the families they hold.
+    /// Sets that don't exist or can't match a source address are left out with a
+    /// warning, another tool may still create them before the next apply.
+    pub fn external_sets(&self, rule_parser: &RuleParser) -> HashMap<String, Vec<Family>> {
+        let mut sets = HashMap::new();
+        for rule in rule_parser.get_ipset_rules() {
+            if sets.contains_key(&rule.name) {
+                continue;
+            }
+            let Some(header) = self.set_header(&rule.name) else {
+                warn!(
+                    "ipset '{}' does not exist, its rules are left out until the next apply",
+                    rule.name
+                );
+                continue;
+            };
+            let families = header.source_families();
+            if families.is_empty() {
+                warn!(
+                    "ipset '{}' has type {} and can't match source addresses, its rules are left out",
+                    rule.name, header.set_type
+                );
+                continue;
+            }
+            sets.insert(rule.name.clone(), families);
+        }
+        sets
+    }
+
+    /// Create an empty list set unless it already exists, so chains can match
+    /// on a list before its first fetch.
+    pub fn ensure_list_set(&self, set_name: &str, family: Family) -> Result<()> {
+        if self.set_header(set_name).is_none() {
+            self.create_or_reset_ipset(set_name, family, MIN_MAXELEM)?;
+        }
+        Ok(())
+    }
+
+    /// Replace the contents of a list set without it ever going empty: the new
+    /// entries are loaded into a scratch set which is then swapped in.
+    pub fn replace_set(&self, set_name: &str, family: Family, values: &[&str]) -> Result<()> {
+        let tmp_name = format!("{}-tmp", set_name);
+        let maxelem = values.len().next_power_of_two().max(MIN_MAXELEM);
+
+        // `ipset swap` needs both sides to exist with the same type and family.
+        // Sets left behind by older versions (hash:ip, always inet) are recreated.
+        match self.set_header(set_name) {
+            Some(header) if header.is_list_set(Self::family_name(family)) => {}
+            Some(header) => {
+                warn!(
+                    "Recreating {} as hash:net {} (was {} {})",
+                    set_name,
+                    Self::family_name(family),
+                    header.set_type,
+                    header.family.as_deref().unwrap_or("-")
+                );
+                self.execute(&["destroy", set_name]).map_err(|e| {
+                    Error::CommandFailed(format!(
+                        "{} has an incompatible type and is still referenced: {}",
+                        set_name, e
+                    ))
+                })?;
+                self.create_or_reset_ipset(set_name, family, maxelem)?;
+            }
+            None => self.create_or_reset_ipset(set_name, family, maxelem)?,
+        }
+        self.create_or_reset_ipset(&tmp_name, family, maxelem)?;
+
+        let result = self
+            .add_all_to_set(&tmp_name, values)
+            .and_then(|_| self.execute(&["swap", &tmp_name, set_name]));
+
+        let _ = self.execute(&["destroy", &tmp_name]);
+        result?;
+
+        info!("Swapped {} entries into {}", values.len(), set_name);
+        Ok(())
+    }
+
     pub fn register_whitelist_set(&mut self, name: String) {
         info!("Registering whitelist set: {}", name);
         self.whitelist_sets.insert(name);
@@ -75,54 +283,287 @@ impl IpsetController {
 
     pub fn configure_port_rules(&self, rules: &[&Rule]) -> Result<()> {
         info!("Configuring port rules: {:?}", rules);
-        for rule in rules {
-            if let Rule::Port(PortRule { number, protocol }) = rule {
-                let set_name = match protocol {
-                    Protocol::TCP => "ei-allowed-tcp-ports",
-                    Protocol::UDP => "ei-allowed-udp-ports",
-                };
-                self.add_to_set(set_name, &number.to_string())?;
-            }
+        for protocol in Self::SET_PROTOCOLS {
+            self.replace_port_set(
+                &Self::allowed_set_name(protocol),
+                &Self::set_ports(rules.iter().copied(), protocol),
+            )?;
         }
         Ok(())
     }
 
+    /// Swap `ports` into a port set through a scratch set like `replace_set`,
+    /// so ports gone from the config close while the others keep matching.
+    fn replace_port_set(&self, set_name: &str, ports: &[String]) -> Result<()> {
+        let tmp_name = format!("{}-tmp", set_name);
+        self.create_port_set(set_name)?;
+        let _ = self.execute(&["destroy", &tmp_name]);
+        self.create_port_set(&tmp_name)?;
+
+        let ports: Vec<&str> = ports.iter().map(String::as_str).collect();
+        let result = self
+            .add_all_to_set(&tmp_name, &ports)
+            .and_then(|_| self.execute(&["swap", &tmp_name, set_name]));
+
+        let _ = self.execute(&["destroy", &tmp_name]);
+        result?;
+        Ok(())
+    }
+
     fn execute(&self, args: &[&str]) -> Result<String> {
         let mut cmd = self.cmd.clone();
         cmd.args(args).execute()
     }
 
-    fn create_or_reset_port_set(&self, set_name: &str) -> Result<()> {
-        let _ = self.execute(&["destroy", set_name]);
-        self.execute(&["create", set_name, "bitmap:port", "range", "1-65535"])?;
+    /// Create a port set unless it exists. An existing one keeps its ports
+    /// until the next apply swaps in the configured ones.
+    fn create_port_set(&self, set_name: &str) -> Result<()> {
+        self.execute(&[
+            "create",
+            set_name,
+            "bitmap:port",
+            "range",
+            "1-65535",
+            "-exist",
+        ])?;
         Ok(())
     }
 
     pub fn add_to_set(&self, set_name: &str, value: &str) -> Result<()> {
-        self.execute(&["add", set_name, value])?;
+        self.execute(&["add", set_name, value, "-exist"])?;
         Ok(())
     }
 
-    pub fn list_ports(&self) -> Result<Vec<(u16, Protocol)>> {
-        let mut ports = Vec::new();
+    /// Load many entries with a single `ipset restore`, falling back to one
+    /// `ipset add` per entry if the bulk load is rejected.
+    pub fn add_all_to_set(&self, set_name: &str, values: &[&str]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
 
-        if let Ok(output) = self.execute(&["list", "ei-allowed-tcp-ports"]) {
-            ports.extend(
-                Self::parse_ipset_list(&output)
-                    .into_iter()
-                    .map(|port| (port, Protocol::TCP)),
+        let payload: String = values
+            .iter()
+            .map(|value| format!("add {} {}\n", set_name, value))
+            .collect();
+
+        let mut cmd = self.cmd.clone();
+        if let Err(e) = cmd.args(["restore", "-exist"]).execute_with_input(&payload) {
+            warn!(
+                "Bulk load of {} entries into {} failed, adding one by one: {}",
+                values.len(),
+                set_name,
+                e
             );
+            for value in values {
+                self.add_to_set(set_name, value)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Destroy every `ei-*` set. The chains matching them must be gone already.
+    pub fn teardown(&self) -> Result<()> {
+        let output = self.execute(&["list", "-n"])?;
+        for name in output.lines().map(str::trim) {
+            if name.starts_with("ei-") {
+                info!("Destroying set {}", name);
+                self.execute(&["destroy", name])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The `ipset save` lines of every ei set, scratch sets left out.
+    pub fn snapshot(&self) -> Result<String> {
+        let output = self.execute(&["save"])?;
+        Ok(output
+            .lines()
+            .filter(|line| {
+                line.split_whitespace()
+                    .nth(1)
+                    .is_some_and(|name| name.starts_with("ei-") && !name.ends_with("-tmp"))
+            })
+            .map(|line| format!("{}\n", line))
+            .collect())
+    }
+
+    /// Refill every set in `saved` with the members it had. Each one is rebuilt
+    /// under a scratch name and swapped in, as the chains may still match on it.
+    /// Sets created since the snapshot are left in place.
+    pub fn rollback(&self, saved: &str) -> Result<()> {
+        let mut payloads: Vec<(String, String)> = Vec::new();
+        for line in saved.lines() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("create"), Some(name)) => {
+                    let options: Vec<&str> = words.collect();
+                    let scratch = format!("{}-rb", name);
+                    payloads.push((
+                        name.to_string(),
+                        format!("create {} {}\n", scratch, options.join(" ")),
+                    ));
+                }
+                (Some("add"), Some(name)) => {
+                    if let Some((_, payload)) = payloads.iter_mut().find(|(set, _)| set == name) {
+                        let member: Vec<&str> = words.collect();
+                        payload.push_str(&format!("add {}-rb {}\n", name, member.join(" ")));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (name, mut payload) in payloads {
+            let scratch = format!("{}-rb", name);
+            let create = payload.lines().next().unwrap_or_default().to_string();
+            // The set may be gone, recreate it as saved so there is something to swap with
+            payload.push_str(&create.replacen(&scratch, &name, 1));
+            payload.push('\n');
+            payload.push_str(&format!("swap {} {}\ndestroy {}\n", scratch, name, scratch));
+
+            let _ = self.execute(&["destroy", &scratch]);
+            let mut cmd = self.cmd.clone();
+            cmd.args(["restore", "-exist"])
+                .execute_with_input(&payload)
+                .map_err(|e| {
+                    Error::CommandFailed(format!("Could not restore set {}: {}", name, e))
+                })?;
+            info!("Restored set {}", name);
+        }
+
+        Ok(())
+    }
+
+    /// Member count of every ei set, from the headers printed by `ipset list -t`.
+    pub fn set_sizes(&self) -> Result<Vec<(String, usize)>> {
+        let output = self.execute(&["list", "-t"])?;
+        let mut sizes = Vec::new();
+        let mut name = None;
+
+        for line in output.lines() {
+            if let Some(value) = line.strip_prefix("Name: ") {
+                name = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("Number of entries: ") {
+                if let Some(name) = name.take().filter(|name| name.starts_with("ei-")) {
+                    sizes.push((name, value.trim().parse().unwrap_or_default()));
+                }
+            }
+        }
+
+        Ok(sizes)
+    }
+
+    /// Members of every ei set, from the full `ipset list`.
+    pub fn current_sets(&self, state: &mut FirewallState) -> Result<()> {
+        let output = self.execute(&["list"])?;
+        let mut name: Option<String> = None;
+        let mut in_members_section = false;
+
+        for line in output.lines() {
+            if let Some(value) = line.strip_prefix("Name: ") {
+                name = Some(value.trim().to_string()).filter(|name| name.starts_with("ei-"));
+                in_members_section = false;
+                if let Some(name) = &name {
+                    state.sets.entry(name.clone()).or_default();
+                }
+            } else if line == "Members:" {
+                in_members_section = true;
+            } else if line.trim().is_empty() {
+                in_members_section = false;
+            } else if in_members_section {
+                if let Some(name) = &name {
+                    // Members may carry options such as a timeout after the value
+                    let member = line.split_whitespace().next().unwrap_or_default();
+                    state
+                        .sets
+                        .entry(name.clone())
+                        .or_default()
+                        .insert(member.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The port sets as `configure_port_rules` and `configure_with_rules` rebuild
+    /// them, so ports added with `ei add` show as removed like the apply does.
+    pub fn desired_sets(&self, state: &mut FirewallState, rule_parser: &RuleParser) {
+        for name in Self::port_set_names() {
+            state.sets.entry(name).or_default();
+        }
+
+        // `ipset list` prints every port of a range on its own line
+        let port_rules = |rules: Vec<&Rule>| -> Vec<(u16, Protocol)> {
+            rules
+                .into_iter()
+                .filter_map(|rule| match rule {
+                    Rule::Port(PortRule { ports, protocol }) => Some((ports, *protocol)),
+                    _ => None,
+                })
+                .flat_map(|(ports, protocol)| {
+                    Self::set_protocols(protocol)
+                        .flat_map(move |protocol| ports.ports().map(move |port| (port, protocol)))
+                })
+                .collect()
+        };
+        let mut members = Vec::new();
+        for (number, protocol) in port_rules(rule_parser.get_port_rules()) {
+            members.push((Self::allowed_set_name(protocol), number));
+        }
+        for (list, rules) in [
+            ("whitelist", rule_parser.get_whitelist_rules()),
+            ("blacklist", rule_parser.get_blacklist_rules()),
+        ] {
+            for (number, protocol) in port_rules(rules.iter().collect()) {
+                members.push((Self::list_port_set_name(list, protocol), number));
+            }
+        }
+
+        for (list, rules) in [
+            ("whitelist", rule_parser.get_whitelist_rules()),
+            ("blacklist", rule_parser.get_blacklist_rules()),
+        ] {
+            for family in [Family::V4, Family::V6] {
+                let networks = RuleParser::get_networks(rules, family);
+                if !networks.is_empty() {
+                    state.sets.insert(
+                        Self::net_set_name(list, family),
+                        networks.into_iter().collect(),
+                    );
+                }
+            }
         }
 
-        if let Ok(output) = self.execute(&["list", "ei-allowed-udp-ports"]) {
-            ports.extend(
-                Self::parse_ipset_list(&output)
+        for (name, number) in members {
+            state
+                .sets
+                .entry(name)
+                .or_default()
+                .insert(number.to_string());
+        }
+    }
+
+    /// The allowed ports, consecutive ones joined back into ranges.
+    pub fn list_ports(&self) -> Result<Vec<(PortRange, Protocol)>> {
+        let mut ports = Vec::new();
+
+        for protocol in Self::SET_PROTOCOLS {
+            if let Ok(output) = self.execute(&["list", &Self::allowed_set_name(protocol)]) {
+                ports.extend(
+                    PortRange::merge(
+                        Self::parse_ipset_list(&output)
+                            .into_iter()
+                            .map(PortRange::single),
+                    )
                     .into_iter()
-                    .map(|port| (port, Protocol::UDP)),
-            );
+                    .map(|range| (range, protocol)),
+                );
+            }
         }
 
-        ports.sort_by_key(|(port, _)| *port);
+        ports.sort_by_key(|(range, _)| *range);
         Ok(ports)
     }
 
@@ -148,19 +589,28 @@ impl IpsetController {
     }
 
     pub fn add_port(&self, port: u16, protocol: Protocol) -> Result<()> {
-        let set_name = match protocol {
-            Protocol::TCP => "ei-allowed-tcp-ports",
-            Protocol::UDP => "ei-allowed-udp-ports",
-        };
-        self.add_to_set(set_name, &port.to_string())
+        Self::check_runtime_port(protocol)?;
+        for protocol in Self::set_protocols(protocol) {
+            self.add_to_set(&Self::allowed_set_name(protocol), &port.to_string())?;
+        }
+        Ok(())
     }
 
     pub fn remove_port(&self, port: u16, protocol: Protocol) -> Result<()> {
-        let set_name = match protocol {
-            Protocol::TCP => "ei-allowed-tcp-ports",
-            Protocol::UDP => "ei-allowed-udp-ports",
-        };
-        self.execute(&["del", set_name, &port.to_string()])?;
+        Self::check_runtime_port(protocol)?;
+        for protocol in Self::set_protocols(protocol) {
+            self.execute(&["del", &Self::allowed_set_name(protocol), &port.to_string()])?;
+        }
+        Ok(())
+    }
+
+    /// SCTP ports are rules in the chains, only an apply can change them.
+    pub fn check_runtime_port(protocol: Protocol) -> Result<()> {
+        if matches!(protocol, Protocol::SCTP) {
+            return Err(Error::Unsupported(
+                "SCTP ports can't be changed at runtime with the iptables backend, use a port rule in the config instead".to_string(),
+            ));
+        }
         Ok(())
     }
 }
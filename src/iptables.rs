@@ -1,24 +1,177 @@
 use log::info;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
 
-use crate::cmd::CmdBuilder;
-use crate::config::Config;
+use crate::backend::{Family, RuleCounter};
+use crate::cmd::{CmdBuilder, Executor, SystemExecutor};
+use crate::config::{BaselineConfig, Config, FinalAction};
 use crate::error::Result;
+use crate::guard;
 use crate::ipset::IpsetController;
-use crate::rules::{Rule, RuleParser};
+use crate::plan::FirewallState;
+use crate::rules::{PortRange, PortRule, Protocol, Rule, RuleParser, Source};
+
+/// Every chain ei may create, in the order they are hooked into `ei`.
+pub const CHAINS: &[&str] = &[
+    "ei",
+    "ei-badtcp",
+    "ei-whitelist",
+    "ei-guard",
+    "ei-blacklist",
+    "ei-ports",
+    "ei-docker",
+];
+
+/// TCP flag combinations no legitimate stack sends, as `--tcp-flags MASK COMP`.
+/// Spelled the way `iptables-save` prints them so `plan` sees no difference.
+const BAD_TCP_FLAGS: &[(&str, &str)] = &[
+    // NULL scan
+    ("FIN,SYN,RST,PSH,ACK,URG", "NONE"),
+    // XMAS scans
+    ("FIN,SYN,RST,PSH,ACK,URG", "FIN,PSH,URG"),
+    ("FIN,SYN,RST,PSH,ACK,URG", "FIN,SYN,RST,PSH,ACK,URG"),
+    ("FIN,SYN", "FIN,SYN"),
+    ("SYN,RST", "SYN,RST"),
+    ("FIN,RST", "FIN,RST"),
+    ("FIN,ACK", "FIN"),
+    ("PSH,ACK", "PSH"),
+    ("ACK,URG", "URG"),
+];
+
+/// Accepts whatever belongs to a connection conntrack already let through.
+const ACCEPT_ESTABLISHED: &[&str] = &[
+    "-m",
+    "conntrack",
+    "--ctstate",
+    "RELATED,ESTABLISHED",
+    "-j",
+    "ACCEPT",
+];
+
+/// Rules ei adds to chains it doesn't own.
+const HOOKS: [(&str, &[&str]); 5] = [
+    ("INPUT", &["-j", "ei"]),
+    ("FORWARD", &["-j", "ei"]),
+    ("INPUT", &["-i", "lo", "-j", "ACCEPT"]),
+    ("FORWARD", &["-i", "lo", "-j", "ACCEPT"]),
+    ("DOCKER-USER", &["-j", "ei-docker"]),
+];
+
+/// The filter table content of one `iptables-restore --noflush` transaction.
+///
+/// Declaring a user chain in a `--noflush` restore creates it, or flushes it
+/// when it already exists, so every declared chain is rebuilt from `rules`
+/// while chains owned by others (INPUT, DOCKER-USER, ...) are left alone.
+#[derive(Debug, Default)]
+pub struct Ruleset {
+    chains: Vec<String>,
+    rules: Vec<String>,
+}
+
+impl Ruleset {
+    pub fn new() -> Self {
+        Ruleset::default()
+    }
+
+    pub fn chain(&mut self, name: &str) {
+        self.chains.push(name.to_string());
+    }
+
+    pub fn append(&mut self, chain: &str, args: &[&str]) {
+        self.rules.push(format!("-A {} {}", chain, args.join(" ")));
+    }
+
+    /// Delete the first rule in `chain` matching `args`.
+    pub fn delete(&mut self, chain: &str, args: &[&str]) {
+        self.rules.push(format!("-D {} {}", chain, args.join(" ")));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chains.is_empty() && self.rules.is_empty()
+    }
+
+    /// The appended rules grouped by chain, declared chains included even when empty.
+    pub fn chain_rules(&self) -> BTreeMap<String, Vec<String>> {
+        let mut chains: BTreeMap<String, Vec<String>> = self
+            .chains
+            .iter()
+            .map(|chain| (chain.clone(), Vec::new()))
+            .collect();
+        for rule in &self.rules {
+            if let Some(chain) = rule.strip_prefix("-A ").and_then(|r| r.split(' ').next()) {
+                chains
+                    .entry(chain.to_string())
+                    .or_default()
+                    .push(rule.clone());
+            }
+        }
+        chains
+    }
+
+    pub fn render(&self) -> String {
+        let mut payload = String::from("*filter\n");
+        for chain in &self.chains {
+            payload.push_str(&format!(":{} - [0:0]\n", chain));
+        }
+        for rule in &self.rules {
+            payload.push_str(rule);
+            payload.push('\n');
+        }
+        payload.push_str("COMMIT\n");
+        payload
+    }
+}
 
 pub struct IptablesController {
     cmd_v4: CmdBuilder,
     cmd_v6: CmdBuilder,
+    restore_v4: CmdBuilder,
+    restore_v6: CmdBuilder,
+    save_v4: CmdBuilder,
+    save_v6: CmdBuilder,
+    /// Where the SSH guard looks for sessions
+    proc_root: PathBuf,
 }
 
 impl IptablesController {
     pub fn new(dry_run: bool) -> Self {
+        Self::with_executor(dry_run, Arc::new(SystemExecutor))
+    }
+
+    pub fn with_executor(dry_run: bool, executor: Arc<dyn Executor>) -> Self {
+        let cmd = |program: &str| {
+            CmdBuilder::new(program)
+                .with_dry_run(dry_run)
+                .with_executor(executor.clone())
+        };
+        let mut restore_v4 = cmd("iptables-restore");
+        restore_v4.arg("--noflush");
+        let mut restore_v6 = cmd("ip6tables-restore");
+        restore_v6.arg("--noflush");
+        let mut save_v4 = cmd("iptables-save");
+        save_v4.args(["-t", "filter"]);
+        let mut save_v6 = cmd("ip6tables-save");
+        save_v6.args(["-t", "filter"]);
+
         IptablesController {
-            cmd_v4: CmdBuilder::new("iptables").with_dry_run(dry_run),
-            cmd_v6: CmdBuilder::new("ip6tables").with_dry_run(dry_run),
+            cmd_v4: cmd("iptables"),
+            cmd_v6: cmd("ip6tables"),
+            restore_v4,
+            restore_v6,
+            save_v4,
+            save_v6,
+            proc_root: PathBuf::from("/proc"),
         }
     }
 
+    /// Read SSH sessions from `proc_root` instead of `/proc`.
+    #[cfg(test)]
+    pub fn with_proc_root(mut self, proc_root: PathBuf) -> Self {
+        self.proc_root = proc_root;
+        self
+    }
+
     #[inline]
     fn execute_v4(&self, args: &[&str]) -> Result<String> {
         self.cmd_v4.clone().args(args).execute()
@@ -30,188 +183,436 @@ impl IptablesController {
     }
 
     #[inline]
-    fn execute_both(&self, args: &[&str]) -> Result<()> {
-        // Execute for IPv4
-        self.execute_v4(args)?;
-        // Execute for IPv6
-        self.execute_v6(args)?;
+    fn execute(&self, family: Family, args: &[&str]) -> Result<String> {
+        match family {
+            Family::V4 => self.execute_v4(args),
+            Family::V6 => self.execute_v6(args),
+        }
+    }
+
+    /// Apply a ruleset in a single transaction; either all of it lands or none of it.
+    fn restore(&self, family: Family, ruleset: &Ruleset) -> Result<()> {
+        let cmd = match family {
+            Family::V4 => &self.restore_v4,
+            Family::V6 => &self.restore_v6,
+        };
+        cmd.execute_with_input(&ruleset.render())?;
         Ok(())
     }
 
-    pub fn init(&self) -> Result<()> {
-        // Create and reset main chain ei for both IPv4 and IPv6
-        self.create_or_reset_chain("ei")?;
+    /// Read the counters of every rule in the ei chains of both families.
+    pub fn rule_counters(&self) -> Result<Vec<RuleCounter>> {
+        let mut counters = Vec::new();
+        for family in [Family::V4, Family::V6] {
+            for chain in CHAINS {
+                // Optional chains only exist when their feature is enabled
+                if let Ok(output) = self.execute(family, &["-L", chain, "-v", "-x", "-n"]) {
+                    counters.extend(Self::parse_listing(family, chain, &output));
+                }
+            }
+        }
+        Ok(counters)
+    }
 
-        // Add chain ei to INPUT and FORWARD for both IPv4 and IPv6
-        self.add_chain_to_filter("ei")?;
+    /// Parse `iptables -L <chain> -v -x -n`, which prints a chain title, a column
+    /// header, then one `pkts bytes target prot opt in out source destination` line per rule.
+    fn parse_listing(family: Family, chain: &str, output: &str) -> Vec<RuleCounter> {
+        output
+            .lines()
+            .skip(2)
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let packets = fields.next()?.parse().ok()?;
+                let bytes = fields.next()?.parse().ok()?;
+                let rest: Vec<&str> = fields.collect();
+                Some((packets, bytes, rest))
+            })
+            .enumerate()
+            .map(|(index, (packets, bytes, rest))| RuleCounter {
+                family: family.label(),
+                chain: chain.to_string(),
+                position: index + 1,
+                target: rest.first().map(|t| t.to_string()).unwrap_or_default(),
+                rule: rest.join(" "),
+                packets,
+                bytes,
+            })
+            .collect()
+    }
 
-        // Accept loopback traffic
-        self.accept_loopback()?;
+    /// How many copies of `rule` are in `chain`, going by `iptables -S`.
+    fn count_rule(&self, family: Family, chain: &str, rule: &[&str]) -> Result<usize> {
+        let spec = Self::hook_spec(chain, rule);
+        let listing = self.execute(family, &["-S", chain])?;
+        Ok(listing.lines().filter(|line| *line == spec).count())
+    }
 
+    fn has_chain(&self, family: Family, chain: &str) -> bool {
+        let declaration = format!("-N {}", chain);
+        self.execute(family, &["-S", chain])
+            .is_ok_and(|listing| listing.lines().any(|line| line == declaration))
+    }
+
+    /// Queue whatever it takes for `chain` to hold exactly one copy of `rule`:
+    /// append it when missing, delete the extra copies otherwise.
+    fn converge_rule(
+        &self,
+        ruleset: &mut Ruleset,
+        family: Family,
+        chain: &str,
+        rule: &[&str],
+    ) -> Result<()> {
+        let copies = self.count_rule(family, chain, rule)?;
+        if copies == 0 {
+            ruleset.append(chain, rule);
+        }
+        for _ in 1..copies {
+            ruleset.delete(chain, rule);
+        }
         Ok(())
     }
 
-    pub fn configure(&self, config: &Config) -> Result<()> {
-        // Configure features
-        if config.features.portforward {
-            self.configure_port_forwarding()?;
+    pub fn init(&self, baseline: &BaselineConfig) -> Result<()> {
+        info!("Initializing ei chain");
+        for family in [Family::V4, Family::V6] {
+            let mut ruleset = Ruleset::new();
+
+            // Create main chain ei, established connections keep flowing until
+            // the config is rendered into it. One left by the previous run keeps
+            // enforcing its rules until `configure_with_rules` replaces it.
+            if !self.has_chain(family, "ei") {
+                ruleset.chain("ei");
+                if baseline.accept_established {
+                    ruleset.append("ei", ACCEPT_ESTABLISHED);
+                }
+            }
+
+            // Add chain ei to INPUT and FORWARD
+            self.converge_rule(&mut ruleset, family, "INPUT", &["-j", "ei"])?;
+            self.converge_rule(&mut ruleset, family, "FORWARD", &["-j", "ei"])?;
+
+            // Accept loopback traffic
+            let loopback = ["-i", "lo", "-j", "ACCEPT"];
+            self.converge_rule(&mut ruleset, family, "INPUT", &loopback)?;
+            self.converge_rule(&mut ruleset, family, "FORWARD", &loopback)?;
+
+            self.restore(family, &ruleset)?;
         }
 
-        if config.features.block_badtcp {
-            self.configure_badtcp()?;
+        Ok(())
+    }
+
+    /// Remove everything ei installed in the filter table: the hooks in INPUT,
+    /// FORWARD and DOCKER-USER, the loopback accepts and every ei chain.
+    /// Missing pieces are skipped, so this can run against a half-applied state.
+    pub fn teardown(&self) -> Result<()> {
+        for family in [Family::V4, Family::V6] {
+            info!("Removing ei chains for {:?}", family);
+
+            for (chain, rule) in HOOKS {
+                // DOCKER-USER only exists while Docker is running
+                let Ok(copies) = self.count_rule(family, chain, rule) else {
+                    continue;
+                };
+
+                // Every copy goes, older versions appended one on each start
+                for _ in 0..copies {
+                    self.execute(family, &[&["-D", chain][..], rule].concat())?;
+                }
+            }
+
+            // Flush first so no ei chain is still referenced by another when deleting
+            for chain in CHAINS {
+                let _ = self.execute(family, &["-F", chain]);
+            }
+            for chain in CHAINS {
+                let _ = self.execute(family, &["-X", chain]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn render_features(&self, ruleset: &mut Ruleset, config: &Config, rule_parser: &RuleParser) {
+        // Configure features
+        if config.features.portforward {
+            self.render_port_forwarding(ruleset, rule_parser);
         }
 
         // Configure Docker blocking
         if config.docker {
-            self.configure_docker_blacklist()?;
+            self.render_docker_blacklist(ruleset);
         }
 
         // Configure interface blocking
-        self.configure_interface_blocking(&config.interfaces)?;
-
-        Ok(())
+        for interface in &config.interfaces {
+            info!("Blocking interface: {}", interface);
+            ruleset.append("ei", &["-i", interface, "-j", "DROP"]);
+        }
     }
 
-    // Split configure into smaller, focused methods
-    fn configure_port_forwarding(&self) -> Result<()> {
-        self.create_or_reset_chain("ei-ports")?;
-        self.add_chain_to_chain("ei-ports", "ei")?;
-        self.add_ipset_rules_to_ports()
-    }
+    fn render_port_forwarding(&self, ruleset: &mut Ruleset, rule_parser: &RuleParser) {
+        info!("Adding ipset rules to ports");
+        ruleset.chain("ei-ports");
+        ruleset.append("ei", &["-j", "ei-ports"]);
 
-    fn configure_badtcp(&self) -> Result<()> {
-        self.create_or_reset_chain("ei-badtcp")?;
-        self.add_chain_to_chain("ei-badtcp", "ei")?;
-        self.implement_badtcp_rules()
+        for protocol in IpsetController::SET_PROTOCOLS {
+            ruleset.append(
+                "ei-ports",
+                &[
+                    "-p",
+                    &protocol.to_string(),
+                    "-m",
+                    "set",
+                    "--match-set",
+                    &IpsetController::allowed_set_name(protocol),
+                    "dst",
+                    "-j",
+                    "ACCEPT",
+                ],
+            );
+        }
+        Self::render_sctp_ports(ruleset, "ei-ports", rule_parser.get_port_rules(), "ACCEPT");
     }
 
-    fn configure_docker_blacklist(&self) -> Result<()> {
-        self.create_or_reset_chain("ei-docker")?;
-        self.add_chain_to_chain("ei-docker", "DOCKER-USER")?;
-        self.implement_docker_blacklist_rules()
-    }
+    /// SCTP port rules as multiport matches, `bitmap:port` sets never match
+    /// SCTP packets. A multiport match takes at most 15 ports, a range
+    /// counting as two.
+    fn render_sctp_ports<'a>(
+        ruleset: &mut Ruleset,
+        chain: &str,
+        rules: impl IntoIterator<Item = &'a Rule>,
+        target: &str,
+    ) {
+        let ranges = PortRange::merge(rules.into_iter().filter_map(|rule| match rule {
+            Rule::Port(PortRule {
+                ports,
+                protocol: Protocol::SCTP,
+            }) => Some(*ports),
+            _ => None,
+        }));
+
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        let mut used = 0;
+        for range in ranges {
+            let (entry, weight) = if range.first == range.last {
+                (range.first.to_string(), 1)
+            } else {
+                (format!("{}:{}", range.first, range.last), 2)
+            };
+            match groups.last_mut() {
+                Some(group) if used + weight <= 15 => {
+                    group.push(entry);
+                    used += weight;
+                }
+                _ => {
+                    groups.push(vec![entry]);
+                    used = weight;
+                }
+            }
+        }
 
-    fn configure_interface_blocking(&self, interfaces: &[String]) -> Result<()> {
-        for interface in interfaces {
-            self.block_interface(interface)?;
+        for group in groups {
+            ruleset.append(
+                chain,
+                &[
+                    "-p",
+                    "sctp",
+                    "-m",
+                    "multiport",
+                    "--dports",
+                    &group.join(","),
+                    "-j",
+                    target,
+                ],
+            );
         }
-        Ok(())
     }
 
-    fn implement_docker_blacklist_rules(&self) -> Result<()> {
-        info!("Implementing Docker blacklist rules");
-        self.execute_both(&[
-            "-A",
-            "ei-docker",
-            "-m",
-            "set",
-            "--match-set",
-            "ei-blacklist",
-            "src",
-            "-j",
-            "DROP",
-        ])
-    }
-
-    fn create_or_reset_chain(&self, chain_name: &str) -> Result<()> {
-        info!("Creating or resetting chain: {}", chain_name);
-        // Try to create new chain (might fail if exists)
-        self.execute_both(&["-N", chain_name])?;
-
-        // Flush the chain (remove all rules)
-        self.execute_both(&["-F", chain_name])
-    }
-
-    fn add_chain_to_filter(&self, chain_name: &str) -> Result<()> {
-        info!("Adding chain to INPUT/FOWARD: {}", chain_name);
-        self.execute_both(&["-A", "INPUT", "-j", chain_name])?;
-        self.execute_both(&["-A", "FORWARD", "-j", chain_name])
-    }
-
-    fn add_chain_to_chain(&self, source_chain: &str, target_chain: &str) -> Result<()> {
-        info!(
-            "Adding chain to chain: {} -> {}",
-            source_chain, target_chain
+    fn render_badtcp(&self, ruleset: &mut Ruleset, family: Family, check_mss: bool) {
+        info!("Implementing badtcp rules");
+        ruleset.chain("ei-badtcp");
+        ruleset.append("ei", &["-j", "ei-badtcp"]);
+
+        ruleset.append(
+            "ei-badtcp",
+            &["-m", "conntrack", "--ctstate", "INVALID", "-j", "DROP"],
         );
-        // First remove any existing references
-        self.execute_both(&["-D", target_chain, "-j", source_chain])?;
 
-        // Then add the chain reference
-        self.execute_both(&["-A", target_chain, "-j", source_chain])
-    }
+        for (mask, comp) in BAD_TCP_FLAGS {
+            ruleset.append(
+                "ei-badtcp",
+                &[
+                    "-p",
+                    "tcp",
+                    "-m",
+                    "tcp",
+                    "--tcp-flags",
+                    mask,
+                    comp,
+                    "-j",
+                    "DROP",
+                ],
+            );
+        }
+
+        // A new connection has to start with a bare SYN
+        ruleset.append(
+            "ei-badtcp",
+            &[
+                "-p",
+                "tcp",
+                "-m",
+                "tcp",
+                "!",
+                "--tcp-flags",
+                "FIN,SYN,RST,ACK",
+                "SYN",
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "NEW",
+                "-j",
+                "DROP",
+            ],
+        );
 
-    fn block_interface(&self, interface: &str) -> Result<()> {
-        info!("Blocking interface: {}", interface);
-        self.execute_both(&["-A", "ei", "-i", interface, "-j", "DROP"])
+        // TCP segments are small enough to never need fragmenting
+        let fragments: &[&str] = match family {
+            Family::V4 => &["-p", "tcp", "-f", "-j", "DROP"],
+            Family::V6 => &["-p", "tcp", "-m", "frag", "-j", "DROP"],
+        };
+        ruleset.append("ei-badtcp", fragments);
+
+        if check_mss {
+            // The smallest MSS the minimum MTU of each family allows. The
+            // negated match also catches SYNs without an MSS option at all.
+            let mss = match family {
+                Family::V4 => "536:65535",
+                Family::V6 => "1220:65535",
+            };
+            ruleset.append(
+                "ei-badtcp",
+                &[
+                    "-p",
+                    "tcp",
+                    "-m",
+                    "conntrack",
+                    "--ctstate",
+                    "NEW",
+                    "-m",
+                    "tcpmss",
+                    "!",
+                    "--mss",
+                    mss,
+                    "-j",
+                    "DROP",
+                ],
+            );
+        }
     }
 
-    fn accept_loopback(&self) -> Result<()> {
-        info!("Accepting loopback traffic");
-        self.execute_both(&["-A", "INPUT", "-i", "lo", "-j", "ACCEPT"])?;
-        self.execute_both(&["-A", "FORWARD", "-i", "lo", "-j", "ACCEPT"])
+    fn render_docker_blacklist(&self, ruleset: &mut Ruleset) {
+        info!("Implementing Docker blacklist rules");
+        ruleset.chain("ei-docker");
+        // Published container ports skip INPUT, so they go through the same blacklist
+        ruleset.append("ei-docker", &["-j", "ei-blacklist"]);
     }
 
-    fn add_ipset_rules_to_ports(&self) -> Result<()> {
-        info!("Adding ipset rules to ports");
-        // Add TCP rules for both IPv4 and IPv6
-        self.execute_both(&[
-            "-A",
-            "ei-ports",
-            "-p",
-            "tcp",
-            "-m",
-            "set",
-            "--match-set",
-            "ei-allowed-tcp-ports",
-            "dst",
-            "-j",
-            "ACCEPT",
-        ])?;
-
-        // Add UDP rules for both IPv4 and IPv6
-        self.execute_both(&[
-            "-A",
-            "ei-ports",
-            "-p",
-            "udp",
-            "-m",
-            "set",
-            "--match-set",
-            "ei-allowed-udp-ports",
-            "dst",
-            "-j",
-            "ACCEPT",
-        ])?;
+    /// Build the complete set of ei chains for one address family.
+    fn render(
+        &self,
+        family: Family,
+        config: &Config,
+        rule_parser: &RuleParser,
+        ipset: &IpsetController,
+        external: &HashMap<String, Vec<Family>>,
+    ) -> Ruleset {
+        let mut ruleset = Ruleset::new();
+        ruleset.chain("ei");
 
-        Ok(())
-    }
+        // Malformed packets go before anything gets a chance to accept them,
+        // established connections included
+        if config.features.block_badtcp {
+            self.render_badtcp(&mut ruleset, family, config.features.badtcp_check_mss);
+        }
 
-    fn implement_badtcp_rules(&self) -> Result<()> {
-        info!("Implementing badtcp rules");
-        self.execute_both(&[
-            "-A",
-            "ei",
-            "-p",
-            "tcp",
-            "--tcp-flags",
-            "ALL",
-            "NONE",
-            "-j",
-            "DROP",
-        ])?;
-        todo!("add actual rules here");
-    }
-
-    fn add_chain_to_chain_start(&self, source_chain: &str, target_chain: &str) -> Result<()> {
-        info!(
-            "Adding chain to chain at the beginning: {} -> {}",
-            source_chain, target_chain
+        if config.baseline.accept_established {
+            ruleset.append("ei", ACCEPT_ESTABLISHED);
+        }
+
+        // Whitelists (highest priority)
+        self.render_whitelist_chain(
+            &mut ruleset,
+            family,
+            ipset,
+            external,
+            rule_parser.get_whitelist_rules(),
         );
-        // First remove any existing references
-        let _ = self.execute_both(&["-D", target_chain, "-j", source_chain]);
 
-        // Then add the chain reference at the beginning
-        self.execute_both(&["-I", target_chain, "1", "-j", source_chain])
+        // Live SSH sessions must survive whatever the blacklists say
+        if config.ssh_guard.enabled {
+            self.render_guard_chain(&mut ruleset, family);
+        }
+
+        // Blacklists next
+        self.render_blacklist_chain(
+            &mut ruleset,
+            family,
+            ipset,
+            external,
+            rule_parser.get_blacklist_rules(),
+        );
+
+        // Services and firewall features
+        self.render_features(&mut ruleset, config, rule_parser);
+
+        self.render_final_action(&mut ruleset, family, &config.baseline);
+
+        ruleset
+    }
+
+    /// The last rule of `ei`, commented with the configured action so it can be
+    /// told apart in `iptables -S`.
+    fn render_final_action(
+        &self,
+        ruleset: &mut Ruleset,
+        family: Family,
+        baseline: &BaselineConfig,
+    ) {
+        let comment = format!("\"ei final action: {}\"", baseline.final_action.label());
+        let target: &[&str] = match (baseline.final_action, family) {
+            (FinalAction::Drop, _) => &["-j", "DROP"],
+            (FinalAction::Reject, Family::V4) => {
+                &["-j", "REJECT", "--reject-with", "icmp-port-unreachable"]
+            }
+            (FinalAction::Reject, Family::V6) => {
+                &["-j", "REJECT", "--reject-with", "icmp6-port-unreachable"]
+            }
+            (FinalAction::Return, _) => &["-j", "RETURN"],
+        };
+
+        let matches: Vec<Vec<&str>> = if baseline.wan_interfaces.is_empty() {
+            vec![vec!["!", "-i", "lo"]]
+        } else {
+            baseline
+                .wan_interfaces
+                .iter()
+                .map(|interface| vec!["-i", interface.as_str()])
+                .collect()
+        };
+
+        for interface in matches {
+            ruleset.append(
+                "ei",
+                &[
+                    &interface[..],
+                    &["-m", "comment", "--comment", &comment],
+                    target,
+                ]
+                .concat(),
+            );
+        }
     }
 
     pub fn configure_with_rules(
@@ -220,100 +621,439 @@ impl IptablesController {
         rule_parser: &RuleParser,
         ipset: &IpsetController,
     ) -> Result<()> {
-        // Configure whitelists first (highest priority)
-        self.configure_whitelist_chain(rule_parser, ipset)?;
+        ipset.replace_list_port_sets("whitelist", rule_parser.get_whitelist_rules())?;
+        ipset.replace_list_port_sets("blacklist", rule_parser.get_blacklist_rules())?;
 
-        // Configure blacklists next
-        self.configure_blacklist_chain(rule_parser, ipset)?;
+        // The address sets have to exist before the chains match on them
+        for (list, rules) in [
+            ("whitelist", rule_parser.get_whitelist_rules()),
+            ("blacklist", rule_parser.get_blacklist_rules()),
+        ] {
+            for family in [Family::V4, Family::V6] {
+                let networks = RuleParser::get_networks(rules, family);
+                if !networks.is_empty() {
+                    let networks: Vec<&str> = networks.iter().map(String::as_str).collect();
+                    ipset.replace_set(
+                        &IpsetController::net_set_name(list, family),
+                        family,
+                        &networks,
+                    )?;
+                }
+            }
+        }
+
+        // On start the chains are applied before any list is fetched
+        for list in rule_parser.get_source_lists() {
+            for family in [Family::V4, Family::V6] {
+                ipset.ensure_list_set(&format!("ei-{}-{}", list.name, family.label()), family)?;
+            }
+        }
+
+        let external = ipset.external_sets(rule_parser);
+        for family in [Family::V4, Family::V6] {
+            info!("Applying ei chains for {:?}", family);
+            let ruleset = self.render(family, config, rule_parser, ipset, &external);
+            self.restore(family, &ruleset)?;
+        }
 
-        // Configure services and firewall features
-        self.configure(config)?;
+        // DOCKER-USER belongs to Docker, so it is never part of our transaction
+        if config.docker {
+            for family in [Family::V4, Family::V6] {
+                let mut ruleset = Ruleset::new();
+                self.converge_rule(&mut ruleset, family, "DOCKER-USER", &["-j", "ei-docker"])?;
+                if !ruleset.is_empty() {
+                    info!("Hooking ei-docker into DOCKER-USER for {:?}", family);
+                    self.restore(family, &ruleset)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
 
+    /// The ei chains and hooks as installed, read from `iptables-save`.
+    pub fn current_rules(&self, state: &mut FirewallState) -> Result<()> {
+        for family in [Family::V4, Family::V6] {
+            for line in self.save(family)? {
+                if let Some(chain) = Self::rule_chain(&line) {
+                    state
+                        .rules
+                        .entry((family.label(), chain.to_string()))
+                        .or_default()
+                        .push(line);
+                }
+            }
+        }
         Ok(())
     }
 
-    fn configure_whitelist_chain(
+    /// The chain an `-A` line appends to.
+    fn rule_chain(line: &str) -> Option<&str> {
+        line.strip_prefix("-A ")?.split(' ').next()
+    }
+
+    fn hook_spec(chain: &str, rule: &[&str]) -> String {
+        format!("-A {} {}", chain, rule.join(" "))
+    }
+
+    /// The ei-owned lines of `iptables-save`: the declarations and rules of the
+    /// ei chains, and the hooks into chains owned by others.
+    pub fn save(&self, family: Family) -> Result<Vec<String>> {
+        let save = match family {
+            Family::V4 => &self.save_v4,
+            Family::V6 => &self.save_v6,
+        };
+        let hooks: Vec<String> = HOOKS
+            .iter()
+            .map(|(chain, rule)| Self::hook_spec(chain, rule))
+            .collect();
+
+        Ok(save
+            .execute()?
+            .lines()
+            .filter(|line| {
+                let declared = line
+                    .strip_prefix(':')
+                    .and_then(|rest| rest.split(' ').next());
+                let chain = declared.or_else(|| Self::rule_chain(line));
+                chain.is_some_and(|chain| CHAINS.contains(&chain))
+                    || hooks.iter().any(|hook| hook == line)
+            })
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Put the ei chains and hooks back the way `save` found them. Chains
+    /// created since are left empty.
+    pub fn rollback(&self, family: Family, saved: &[String]) -> Result<()> {
+        let current = self.save(family)?;
+        let declared: HashSet<&str> = saved
+            .iter()
+            .chain(current.iter())
+            .filter_map(|line| line.strip_prefix(':')?.split(' ').next())
+            .collect();
+
+        let mut ruleset = Ruleset::new();
+        for chain in CHAINS.iter().filter(|chain| declared.contains(*chain)) {
+            ruleset.chain(chain);
+        }
+        for line in saved {
+            if Self::rule_chain(line).is_some_and(|chain| CHAINS.contains(&chain)) {
+                ruleset.rules.push(line.clone());
+            }
+        }
+
+        // Hooks live in chains we don't flush, so only the difference is applied
+        for (chain, rule) in HOOKS {
+            let spec = Self::hook_spec(chain, rule);
+            let wanted = saved.iter().filter(|line| **line == spec).count();
+            let installed = current.iter().filter(|line| **line == spec).count();
+            for _ in installed..wanted {
+                ruleset.append(chain, rule);
+            }
+            for _ in wanted..installed {
+                ruleset.delete(chain, rule);
+            }
+        }
+
+        if ruleset.is_empty() {
+            return Ok(());
+        }
+        info!("Rolling back ei chains for {:?}", family);
+        self.restore(family, &ruleset)
+    }
+
+    /// The ei chains and hooks `init` and `configure_with_rules` would install.
+    pub fn desired_rules(
         &self,
+        state: &mut FirewallState,
+        config: &Config,
         rule_parser: &RuleParser,
         ipset: &IpsetController,
-    ) -> Result<()> {
-        self.create_or_reset_chain("ei-whitelist")?;
-        self.add_chain_to_chain_start("ei-whitelist", "ei")?;
+    ) {
+        let external = ipset.external_sets(rule_parser);
+        for family in [Family::V4, Family::V6] {
+            let ruleset = self.render(family, config, rule_parser, ipset, &external);
+            for (chain, rules) in ruleset.chain_rules() {
+                state.rules.insert((family.label(), chain), rules);
+            }
 
-        rule_parser.get_whitelist_rules().iter().for_each(|rule| {
-            if let Rule::Port(port) = rule {
-                ipset.add_to_whitelist(port.number, port.protocol).unwrap();
+            for (chain, rule) in HOOKS {
+                if chain == "DOCKER-USER" && !config.docker {
+                    continue;
+                }
+                state
+                    .rules
+                    .entry((family.label(), chain.to_string()))
+                    .or_default()
+                    .push(Self::hook_spec(chain, rule));
             }
+        }
+    }
+
+    /// The list sets matching this family, e.g. `ei-cloudflare-ipv6` for IPv6.
+    fn list_set_names(sets: &HashSet<String>, family: Family) -> Vec<String> {
+        let mut names: Vec<String> = sets
+            .iter()
+            .map(|e| format!("ei-{}-{}", e, family.label()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The set holding the `ip:` rules of a list, if it has any for this family.
+    fn net_set_name(list: &str, family: Family, rules: &[Rule]) -> Option<String> {
+        if RuleParser::get_networks(rules, family).is_empty() {
+            None
+        } else {
+            Some(IpsetController::net_set_name(list, family))
+        }
+    }
+
+    /// Whether an externally managed set can match source addresses of this family.
+    fn holds(external: &HashMap<String, Vec<Family>>, set_name: &str, family: Family) -> bool {
+        external
+            .get(set_name)
+            .is_some_and(|families| families.contains(&family))
+    }
+
+    /// The externally managed sets of the list's `ipset:` rules holding this family.
+    fn external_set_names(
+        rules: &[Rule],
+        family: Family,
+        external: &HashMap<String, Vec<Family>>,
+    ) -> Vec<String> {
+        rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::IpSet(set) if Self::holds(external, &set.name, family) => {
+                    Some(set.name.clone())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// One rule per compound rule of this family, matching the port and the
+    /// source together.
+    fn render_port_from_rules(
+        ruleset: &mut Ruleset,
+        chain: &str,
+        family: Family,
+        external: &HashMap<String, Vec<Family>>,
+        rules: &[Rule],
+        target: &str,
+    ) {
+        let expanded = RuleParser::get_port_from_rules(rules).flat_map(|rule| {
+            rule.port
+                .protocol
+                .expand()
+                .iter()
+                .map(move |protocol| (rule, protocol))
         });
+        for (rule, protocol) in expanded {
+            let protocol = protocol.to_string();
+            // iptables-save spells a range with a colon
+            let ports = rule.port.ports.to_string().replace('-', ":");
+            let port = ["-p", &protocol, "-m", &protocol, "--dport", &ports];
+
+            let set_name = match &rule.source {
+                Source::Ip(ip) => {
+                    if ip.family() == family {
+                        let source = ip.to_string();
+                        ruleset.append(
+                            chain,
+                            &[&["-s", &source], &port[..], &["-j", target]].concat(),
+                        );
+                    }
+                    continue;
+                }
+                Source::IpList(list) => format!("ei-{}-{}", list.name, family.label()),
+                Source::IpSet(set) if Self::holds(external, &set.name, family) => set.name.clone(),
+                Source::IpSet(_) => continue,
+            };
+            ruleset.append(
+                chain,
+                &[
+                    &port[..],
+                    &["-m", "set", "--match-set", &set_name, "src", "-j", target],
+                ]
+                .concat(),
+            );
+        }
+    }
+
+    /// One rule per `icmp:` or `icmpv6:` rule of this family, with the type as
+    /// the number `iptables-save` prints.
+    fn render_icmp_rules(
+        ruleset: &mut Ruleset,
+        chain: &str,
+        family: Family,
+        rules: &[Rule],
+        target: &str,
+    ) {
+        for icmp_type in RuleParser::get_icmp_types(rules, family) {
+            let icmp_type = icmp_type.to_string();
+            let icmp: &[&str] = match family {
+                Family::V4 => &["-p", "icmp", "-m", "icmp", "--icmp-type"],
+                Family::V6 => &["-p", "ipv6-icmp", "-m", "icmp6", "--icmpv6-type"],
+            };
+            ruleset.append(chain, &[icmp, &[&icmp_type, "-j", target]].concat());
+        }
+    }
+
+    fn render_whitelist_chain(
+        &self,
+        ruleset: &mut Ruleset,
+        family: Family,
+        ipset: &IpsetController,
+        external: &HashMap<String, Vec<Family>>,
+        rules: &[Rule],
+    ) {
+        ruleset.chain("ei-whitelist");
+        ruleset.append("ei", &["-j", "ei-whitelist"]);
 
         // Add ipset rules
-        for set_name in ipset
-            .get_whitelist_sets()
-            .iter()
-            .flat_map(|e| vec![format!("ei-{}-ipv4", e), format!("ei-{}-ipv6", e)])
-            .chain([
-                "ei-whitelist-tcp".to_string(),
-                "ei-whitelist-udp".to_string(),
-            ])
+        for set_name in Self::list_set_names(ipset.get_whitelist_sets(), family)
+            .into_iter()
+            .chain(Self::net_set_name("whitelist", family, rules))
+            .chain(Self::external_set_names(rules, family, external))
         {
-            self.execute_both(&[
-                "-A",
+            ruleset.append(
                 "ei-whitelist",
-                "-m",
-                "set",
-                "--match-set",
-                &set_name,
-                "dst",
-                "-j",
-                "ACCEPT",
-            ])?;
+                &["-m", "set", "--match-set", &set_name, "src", "-j", "ACCEPT"],
+            );
         }
+        Self::render_port_from_rules(ruleset, "ei-whitelist", family, external, rules, "ACCEPT");
+        Self::render_icmp_rules(ruleset, "ei-whitelist", family, rules, "ACCEPT");
 
-        Ok(())
+        Self::render_port_sets(ruleset, "ei-whitelist", "whitelist", rules, "ACCEPT");
+    }
+
+    /// The port sets of the whitelist or blacklist, each only matching its own
+    /// protocol, then the SCTP ports.
+    fn render_port_sets(
+        ruleset: &mut Ruleset,
+        chain: &str,
+        list: &str,
+        rules: &[Rule],
+        target: &str,
+    ) {
+        for protocol in IpsetController::SET_PROTOCOLS {
+            let protocol_name = protocol.to_string();
+            let set_name = IpsetController::list_port_set_name(list, protocol);
+            ruleset.append(
+                chain,
+                &[
+                    "-p",
+                    &protocol_name,
+                    "-m",
+                    "set",
+                    "--match-set",
+                    &set_name,
+                    "dst",
+                    "-j",
+                    target,
+                ],
+            );
+        }
+        Self::render_sctp_ports(ruleset, chain, rules, target);
+    }
+
+    fn render_guard_chain(&self, ruleset: &mut Ruleset, family: Family) {
+        ruleset.chain("ei-guard");
+        ruleset.append("ei", &["-j", "ei-guard"]);
+
+        for session in guard::ssh_sessions(&self.proc_root)
+            .into_iter()
+            .filter(|session| session.family() == family)
+        {
+            info!(
+                "Guarding SSH session from {} port {} to port {}",
+                session.remote, session.remote_port, session.local_port
+            );
+            let prefix = match family {
+                Family::V4 => 32,
+                Family::V6 => 128,
+            };
+            ruleset.append(
+                "ei-guard",
+                &[
+                    "-s",
+                    &format!("{}/{}", session.remote, prefix),
+                    "-p",
+                    "tcp",
+                    "-m",
+                    "tcp",
+                    "--sport",
+                    &session.remote_port.to_string(),
+                    "--dport",
+                    &session.local_port.to_string(),
+                    "-m",
+                    "conntrack",
+                    "--ctstate",
+                    "ESTABLISHED",
+                    "-j",
+                    "ACCEPT",
+                ],
+            );
+        }
     }
 
-    fn configure_blacklist_chain(
+    fn render_blacklist_chain(
         &self,
-        rule_parser: &RuleParser,
+        ruleset: &mut Ruleset,
+        family: Family,
         ipset: &IpsetController,
-    ) -> Result<()> {
-        self.create_or_reset_chain("ei-blacklist")?;
-        self.add_chain_to_chain("ei-blacklist", "ei")?;
+        external: &HashMap<String, Vec<Family>>,
+        rules: &[Rule],
+    ) {
+        ruleset.chain("ei-blacklist");
+        ruleset.append("ei", &["-j", "ei-blacklist"]);
 
-        rule_parser.get_blacklist_rules().iter().for_each(|rule| {
-            if let Rule::Port(port) = rule {
-                ipset.add_to_blacklist(port.number, port.protocol).unwrap();
-            }
-        });
+        Self::render_port_from_rules(ruleset, "ei-blacklist", family, external, rules, "DROP");
+        Self::render_icmp_rules(ruleset, "ei-blacklist", family, rules, "DROP");
 
         // Add ipset rules
-        for set_name in ipset
-            .get_blacklist_sets()
-            .iter()
-            .flat_map(|e| {
-                [
-                    format!("ei-{}-ipv4", e).to_string(),
-                    format!("ei-{}-ipv6", e).to_string(),
-                ]
-            })
-            .chain([
-                "ei-blacklist-tcp".to_string(),
-                "ei-blacklist-udp".to_string(),
-            ])
+        for set_name in Self::list_set_names(ipset.get_blacklist_sets(), family)
+            .into_iter()
+            .chain(Self::net_set_name("blacklist", family, rules))
+            .chain(Self::external_set_names(rules, family, external))
         {
-            self.execute_v4(&[
-                "-A",
+            ruleset.append(
                 "ei-blacklist",
-                "-m",
-                "set",
-                "--match-set",
-                &set_name,
-                "src",
-                "-j",
-                "DROP",
-            ])?;
+                &["-m", "set", "--match-set", &set_name, "src", "-j", "DROP"],
+            );
         }
+        Self::render_port_sets(ruleset, "ei-blacklist", "blacklist", rules, "DROP");
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn sctp_ports_split_into_multiport_rules() {
+        // 13 single ports and a range make 15, the next range starts a new rule
+        let rules: Vec<Rule> = (1..=13)
+            .map(|port| format!("{}/sctp", port * 10))
+            .chain(["200-210/sctp".to_string(), "300-310/sctp".to_string()])
+            .chain(["400/tcp".to_string()])
+            .map(|rule| Rule::from_str(&rule).unwrap())
+            .collect();
+
+        let mut ruleset = Ruleset::new();
+        ruleset.chain("ei-ports");
+        IptablesController::render_sctp_ports(&mut ruleset, "ei-ports", &rules, "ACCEPT");
+
+        assert_eq!(
+            ruleset.chain_rules()["ei-ports"],
+            [
+                "-A ei-ports -p sctp -m multiport --dports 10,20,30,40,50,60,70,80,90,100,110,120,130,200:210 -j ACCEPT",
+                "-A ei-ports -p sctp -m multiport --dports 300:310 -j ACCEPT",
+            ]
+        );
     }
 }
@@ -1,24 +1,43 @@
 #![feature(iterator_try_collect)]
 
+mod apply;
 mod auto;
+mod backend;
 mod cmd;
 mod config;
 mod error;
+mod guard;
 mod ipset;
 mod iptables;
+mod metrics;
+mod nftables;
+mod pidfile;
+mod plan;
 mod rules;
+#[cfg(test)]
+mod snapshot_tests;
 
-use auto::{IpListManager, IpListResolver};
+use apply::ApplyQueue;
+use auto::{IpListCache, IpListManager, IpListResolver, SharedListStatus};
+use backend::{Backend, SharedBackend, Snapshot};
 use clap::{Parser, Subcommand};
-use config::{CliConfig, Config};
-use ipset::IpsetController;
-use iptables::IptablesController;
-use log::{debug, error, info};
+use cmd::CmdBuilder;
+use config::{CliConfig, Config, FeaturesConfig};
+use log::{debug, error, info, warn};
+use metrics::{Metrics, ReloadSummary};
+use pidfile::PidFile;
+use plan::Plan;
 use rules::{IpListConfig, Protocol, RuleParser};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::process::exit;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 use warp::Filter;
 
 #[derive(Parser, Clone)]
@@ -70,12 +89,22 @@ enum Commands {
 
     /// Show current status
     Status,
+
+    /// Remove every chain, hook and set ei installed
+    Teardown,
+
+    /// Show what applying the configuration would change
+    Plan {
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Port {
     number: u16,
-    protocol: String, // "tcp" or "udp"
+    protocol: String, // "tcp", "udp", "sctp" or "any" for both tcp and udp
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -89,13 +118,12 @@ struct PortResponse {
     ports: Vec<String>,
 }
 
-async fn list_ports(ipset: Arc<RwLock<IpsetController>>) -> Result<impl warp::Reply, Infallible> {
-    let ipset = ipset.read().unwrap();
-    match ipset.list_ports() {
+async fn list_ports(backend: SharedBackend) -> Result<impl warp::Reply, Infallible> {
+    match backend::run(&backend, |backend| backend.list_ports()).await {
         Ok(ports) => {
             let formatted_ports: Vec<String> = ports
                 .into_iter()
-                .map(|(port, proto)| format!("{}/{}", port, proto.to_string()))
+                .map(|(ports, proto)| format!("{}/{}", ports, proto.to_string()))
                 .collect();
 
             Ok(warp::reply::with_status(
@@ -112,69 +140,426 @@ async fn list_ports(ipset: Arc<RwLock<IpsetController>>) -> Result<impl warp::Re
     }
 }
 
+#[derive(Debug, Serialize)]
+struct IpListResponse {
+    name: String,
+    from_cache: bool,
+    fetched_at: u64,
+    age_secs: u64,
+    last_error: Option<String>,
+}
+
+async fn list_iplists(list_status: SharedListStatus) -> Result<impl warp::Reply, Infallible> {
+    let mut lists: Vec<IpListResponse> = list_status
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, status)| IpListResponse {
+            name: name.clone(),
+            from_cache: status.from_cache,
+            fetched_at: status.fetched_at,
+            age_secs: status.age().as_secs(),
+            last_error: status.last_error.clone(),
+        })
+        .collect();
+    lists.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(warp::reply::json(&lists))
+}
+
+async fn export_metrics(state: AppState) -> Result<impl warp::Reply, Infallible> {
+    let (counters, set_sizes) = backend::run(&state.backend, |backend| {
+        let counters = backend.rule_counters().unwrap_or_else(|e| {
+            warn!("Failed to read rule counters: {}", e);
+            Vec::new()
+        });
+        let set_sizes = backend.set_sizes().unwrap_or_else(|e| {
+            warn!("Failed to read set sizes: {}", e);
+            Vec::new()
+        });
+        Ok((counters, set_sizes))
+    })
+    .await
+    .unwrap_or_default();
+
+    let body = state
+        .metrics
+        .render(&counters, &set_sizes, &state.list_status.read().unwrap());
+
+    Ok(warp::reply::with_header(
+        body,
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
 #[derive(Clone)]
 struct AppState {
-    ipset: Arc<RwLock<IpsetController>>,
+    backend: SharedBackend,
     config_path: PathBuf,
-    dry_run: bool,
+    refresh_tasks: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    list_status: SharedListStatus,
+    metrics: Arc<Metrics>,
+    listen: SocketAddr,
+    applied: Arc<RwLock<Option<Config>>>,
+    queue: ApplyQueue,
+    pending: Arc<Mutex<Option<PendingApply>>>,
 }
 
-async fn load_and_configure(state: &AppState) -> Result<(), error::Error> {
-    // Load configuration
-    let config = Config::load(&state.config_path)?;
+static NEXT_APPLY_ID: AtomicU64 = AtomicU64::new(0);
 
-    // Initialize components
-    let iptables = IptablesController::new(state.dry_run);
-    let ipset = Arc::clone(&state.ipset);
+/// An apply waiting for `ei confirm`, reverted when its timer fires first.
+struct PendingApply {
+    id: u64,
+    /// The firewall as it was before the apply
+    snapshot: Snapshot,
+    previous: Option<Config>,
+    timer: JoinHandle<()>,
+}
 
-    // Initialize rule parser
-    let mut rule_parser = RuleParser::new();
-    rule_parser.parse_config(&config);
+#[derive(Debug, Serialize, Deserialize)]
+struct StatusResponse {
+    pid: u32,
+    config_path: PathBuf,
+    listen: SocketAddr,
+    backend: Option<Backend>,
+    features: Option<FeaturesConfig>,
+    docker: bool,
+    interfaces: Vec<String>,
+    sets: Vec<(String, usize)>,
+    last_reload: Option<ReloadSummary>,
+}
 
+async fn get_status(state: AppState) -> Result<impl warp::Reply, Infallible> {
+    let sets = backend::run(&state.backend, |backend| backend.set_sizes())
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to read set sizes: {}", e);
+            Vec::new()
+        });
+    let applied = state.applied.read().unwrap();
+
+    Ok(warp::reply::json(&StatusResponse {
+        pid: std::process::id(),
+        config_path: state.config_path.clone(),
+        listen: state.listen,
+        backend: applied.as_ref().map(|config| config.backend),
+        features: applied.as_ref().map(|config| config.features.clone()),
+        docker: applied.as_ref().is_some_and(|config| config.docker),
+        interfaces: applied
+            .as_ref()
+            .map(|config| config.interfaces.clone())
+            .unwrap_or_default(),
+        sets,
+        last_reload: state.metrics.last_reload(),
+    }))
+}
+
+/// Apply the config file, returning the firewall state it replaced.
+async fn load_and_configure(state: &AppState) -> Result<Snapshot, error::Error> {
+    let started = Instant::now();
+    let result = apply_with_rollback(state).await;
+    state.metrics.record_reload(
+        started.elapsed(),
+        result.as_ref().err().map(|e| e.to_string()),
+    );
+    result
+}
+
+/// Apply the config, putting the ei chains and sets back as they were if it fails midway.
+async fn apply_with_rollback(state: &AppState) -> Result<Snapshot, error::Error> {
+    let snapshot = backend::run(&state.backend, |backend| backend.snapshot())
+        .await
+        .map_err(|e| {
+            error::Error::CommandFailed(format!(
+                "Could not snapshot the firewall, not applying: {}",
+                e
+            ))
+        })?;
+
+    let cause = match apply_config(state).await {
+        Ok(()) => return Ok(snapshot),
+        Err(e) => e,
+    };
+    error!("Applying configuration failed: {}", cause);
+
+    let rollback =
+        match backend::run(&state.backend, move |backend| backend.rollback(&snapshot)).await {
+            Ok(()) => {
+                info!("Rolled back to the previous firewall state");
+                "rolled back to the previous state".to_string()
+            }
+            Err(e) => {
+                error!(
+                    "Rolling back failed, the firewall may be partially applied: {}",
+                    e
+                );
+                format!("rollback failed: {}", e)
+            }
+        };
+
+    Err(error::Error::ApplyFailed {
+        cause: Box::new(cause),
+        rollback,
+    })
+}
+
+/// Set up the IP list manager for a config and register the list sets its rules match on.
+async fn build_list_manager(
+    config: &Config,
+    rule_parser: &RuleParser,
+    backend: &SharedBackend,
+    list_status: SharedListStatus,
+) -> IpListManager {
     // Initialize IP list resolver and manager
     let mut resolver = IpListResolver::new();
-    resolver.load_config(&config);
+    resolver.load_config(config);
 
-    let mut ip_list_manager = IpListManager::new(ipset.clone());
+    let mut ip_list_manager = IpListManager::new(
+        backend.clone(),
+        IpListCache::new(config.state_dir().join("iplists")),
+        list_status,
+    );
 
     // Resolve and add IP lists
     for list in resolver.resolve_all(rule_parser.get_iplist_rules().as_slice()) {
         ip_list_manager.add_list(list);
     }
 
-    ip_list_manager.load_from_config(&config);
+    ip_list_manager.load_from_config(config);
 
-    resolver
-        .resolve_all(rule_parser.get_whitelist_rules())
-        .iter()
-        .for_each(|rule| {
-            ip_list_manager.register_whitelist_set(rule.name().to_string());
-        });
+    for rule in resolver.resolve_all(rule_parser.get_whitelist_rules()) {
+        ip_list_manager
+            .register_whitelist_set(rule.name().to_string())
+            .await;
+    }
 
-    resolver
-        .resolve_all(rule_parser.get_blacklist_rules())
-        .iter()
-        .for_each(|rule| {
-            ip_list_manager.register_blacklist_set(rule.name().to_string());
-        });
+    for rule in resolver.resolve_all(rule_parser.get_blacklist_rules()) {
+        ip_list_manager
+            .register_blacklist_set(rule.name().to_string())
+            .await;
+    }
+
+    ip_list_manager
+}
+
+/// Compare the live firewall with what applying `config` would install. List
+/// sets are predicted from their cached copies, nothing is fetched.
+async fn plan_changes(config: &Config, backend: &SharedBackend) -> Result<Plan, error::Error> {
+    let mut rule_parser = RuleParser::new();
+    rule_parser.parse_config(config);
+
+    let ip_list_manager = build_list_manager(
+        config,
+        &rule_parser,
+        backend,
+        Arc::new(RwLock::new(HashMap::new())),
+    )
+    .await;
+
+    let config = config.clone();
+    let (current, mut desired) = backend::run(backend, move |backend| {
+        Ok((
+            backend.current_state()?,
+            backend.desired_state(&config, &rule_parser)?,
+        ))
+    })
+    .await?;
+
+    let mut unknown_sets = Vec::new();
+    for (set_name, entries) in ip_list_manager.cached_sets() {
+        match entries {
+            Some(entries) => {
+                desired.sets.insert(set_name, entries.into_iter().collect());
+            }
+            None => unknown_sets.push(set_name),
+        }
+    }
+
+    let mut plan = Plan::between(&current, &desired);
+    plan.unknown_sets = unknown_sets;
+    Ok(plan)
+}
+
+async fn apply_config(state: &AppState) -> Result<(), error::Error> {
+    // Load configuration
+    let config = Config::load(&state.config_path)?;
+
+    // Initialize components
+    let backend = Arc::clone(&state.backend);
+
+    // Initialize rule parser
+    let mut rule_parser = RuleParser::new();
+    rule_parser.parse_config(&config);
+
+    let ip_list_manager =
+        build_list_manager(&config, &rule_parser, &backend, state.list_status.clone()).await;
 
     // Update all IP lists
     ip_list_manager.update_all().await?;
 
-    // Configure port rules
-    ipset
-        .write()
-        .unwrap()
-        .configure_port_rules(&rule_parser.get_port_rules())?;
+    let applied = config.clone();
+    backend::run(&backend, move |backend| {
+        // Configure port rules
+        backend.configure_port_rules(&rule_parser.get_port_rules())?;
+
+        // Configure firewall rules
+        backend.configure_with_rules(&config, &rule_parser)
+    })
+    .await?;
+
+    // Replace the background refreshes of the previous configuration
+    let tasks = ip_list_manager.schedule_refresh(&state.queue)?;
+    replace_refresh_tasks(state, tasks);
+
+    *state.applied.write().unwrap() = Some(applied);
+
+    Ok(())
+}
+
+fn replace_refresh_tasks(state: &AppState, tasks: Vec<JoinHandle<()>>) {
+    let mut refresh_tasks = state.refresh_tasks.lock().unwrap();
+    for task in refresh_tasks.drain(..) {
+        task.abort();
+    }
+    *refresh_tasks = tasks;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ApplyRequest {
+    /// Revert unless `/confirm` is called within this many seconds
+    confirm_within: Option<u64>,
+}
+
+/// Apply the config and arm the revert timer. Runs on the apply worker.
+async fn apply_unconfirmed(state: AppState, confirm_within: Duration) -> Result<(), String> {
+    if state.pending.lock().unwrap().is_some() {
+        return Err("A previous apply is still waiting for confirmation".to_string());
+    }
+
+    let previous = state.applied.read().unwrap().clone();
+    let snapshot = load_and_configure(&state)
+        .await
+        .map_err(|e| e.to_string())?;
 
-    // Configure iptables rules
-    iptables.configure_with_rules(&config, &rule_parser, &ipset.read().unwrap())?;
+    let id = NEXT_APPLY_ID.fetch_add(1, Ordering::Relaxed);
+
+    let timer_state = state.clone();
+    let timer = tokio::spawn(async move {
+        tokio::time::sleep(confirm_within).await;
+        let queue = timer_state.queue.clone();
+        if let Err(e) = queue.run(revert_unconfirmed(timer_state, id)).await {
+            error!("Reverting the unconfirmed apply failed: {}", e);
+        }
+    });
 
+    *state.pending.lock().unwrap() = Some(PendingApply {
+        id,
+        snapshot,
+        previous,
+        timer,
+    });
+    warn!(
+        "Configuration applied, reverting in {:?} unless confirmed",
+        confirm_within
+    );
     Ok(())
 }
 
+/// Put back the firewall and list refreshes from before apply `id`, unless it
+/// was confirmed in the meantime. Runs on the apply worker.
+async fn revert_unconfirmed(state: AppState, id: u64) -> Result<(), String> {
+    let pending = {
+        let mut pending = state.pending.lock().unwrap();
+        match pending.take() {
+            Some(apply) if apply.id == id => apply,
+            other => {
+                *pending = other;
+                return Ok(());
+            }
+        }
+    };
+
+    warn!("Apply was not confirmed in time, reverting to the previous ruleset");
+    let snapshot = pending.snapshot;
+    backend::run(&state.backend, move |backend| backend.rollback(&snapshot))
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // The sets are back to their old members, keep them refreshed like before
+    if let Some(config) = &pending.previous {
+        let mut rule_parser = RuleParser::new();
+        rule_parser.parse_config(config);
+        let ip_list_manager = build_list_manager(
+            config,
+            &rule_parser,
+            &state.backend,
+            state.list_status.clone(),
+        )
+        .await;
+        let tasks = ip_list_manager
+            .schedule_refresh(&state.queue)
+            .map_err(|e| e.to_string())?;
+        replace_refresh_tasks(&state, tasks);
+    }
+    *state.applied.write().unwrap() = pending.previous;
+
+    info!("Reverted to the previous ruleset");
+    Ok(())
+}
+
+async fn apply_config_handler(
+    request: ApplyRequest,
+    state: AppState,
+) -> Result<impl warp::Reply, Infallible> {
+    let queue = state.queue.clone();
+    let result = match request.confirm_within {
+        Some(secs) => queue
+            .run(apply_unconfirmed(state, Duration::from_secs(secs)))
+            .await
+            .map(|_| {
+                format!(
+                    "Configuration applied, run `ei confirm` within {}s or it will be reverted",
+                    secs
+                )
+            }),
+        None => queue
+            .reload()
+            .await
+            .map(|_| String::from("Configuration applied")),
+    };
+
+    match result {
+        Ok(message) => Ok(warp::reply::with_status(
+            message,
+            warp::http::StatusCode::OK,
+        )),
+        Err(e) => Ok(warp::reply::with_status(
+            format!("Failed to apply configuration: {}", e),
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+        )),
+    }
+}
+
+async fn confirm_apply(state: AppState) -> Result<impl warp::Reply, Infallible> {
+    match state.pending.lock().unwrap().take() {
+        Some(pending) => {
+            pending.timer.abort();
+            info!("Apply confirmed, keeping the new ruleset");
+            Ok(warp::reply::with_status(
+                String::from("Configuration confirmed"),
+                warp::http::StatusCode::OK,
+            ))
+        }
+        None => Ok(warp::reply::with_status(
+            String::from("No apply is waiting for confirmation"),
+            warp::http::StatusCode::CONFLICT,
+        )),
+    }
+}
+
 async fn reload_config(state: AppState) -> Result<impl warp::Reply, Infallible> {
-    match load_and_configure(&state).await {
+    match state.queue.reload().await {
         Ok(_) => Ok(warp::reply::with_status(
             String::from("Configuration reloaded successfully"),
             warp::http::StatusCode::OK,
@@ -207,7 +592,10 @@ async fn main() {
         }
     };
 
-    let ipset = Arc::new(RwLock::new(IpsetController::new(cli.dry_run)));
+    let backend: SharedBackend = Arc::new(tokio::sync::RwLock::new(backend::new_backend(
+        config.backend,
+        cli.dry_run,
+    )));
 
     match cli.command {
         Commands::Start {
@@ -217,6 +605,11 @@ async fn main() {
             badtcp,
             docker,
         } => {
+            if let Some(pid) = PidFile::new(config.pidfile()).running_pid() {
+                error!("eidamon is already running (pid {})", pid);
+                exit(1);
+            }
+
             // Update config with CLI arguments
             let mut config = config;
             config.update_from_cli(CliConfig {
@@ -236,40 +629,42 @@ async fn main() {
                 }
             }
 
-            // Initialize controllers with dry-run mode
-            let iptables = IptablesController::new(cli.dry_run);
-
             debug!("Applying configuration: {:?}", config);
 
             // Initialize and configure components
-            if let Err(e) = iptables.init() {
-                error!("Failed to initialize iptables: {}", e);
+            let baseline = config.baseline.clone();
+            if let Err(e) = backend::run(&backend, move |backend| backend.init(&baseline)).await {
+                error!("Failed to initialize firewall chains: {}", e);
                 return;
             }
 
-            if let Err(e) = ipset.write().unwrap().init() {
-                error!("Failed to initialize ipset: {}", e);
+            if let Err(e) = backend::run(&backend, |backend| backend.init_sets()).await {
+                error!("Failed to initialize firewall sets: {}", e);
                 return;
             }
 
-            // Configure ipset rules
+            // Configure port sets
             let mut rule_parser = RuleParser::new();
             rule_parser.parse_config(&config);
 
-            if let Err(e) = ipset
-                .write()
-                .unwrap()
-                .configure_port_rules(&rule_parser.get_port_rules())
+            let parser = rule_parser.clone();
+            if let Err(e) = backend::run(&backend, move |backend| {
+                backend.configure_port_rules(&parser.get_port_rules())
+            })
+            .await
             {
                 error!("Failed to configure port rules: {}", e);
                 return;
             }
 
-            // Configure iptables rules
-            if let Err(e) =
-                iptables.configure_with_rules(&config, &rule_parser, &ipset.read().unwrap())
+            // Configure firewall rules
+            let applied = config.clone();
+            if let Err(e) = backend::run(&backend, move |backend| {
+                backend.configure_with_rules(&applied, &rule_parser)
+            })
+            .await
             {
-                error!("Failed to configure iptables rules: {}", e);
+                error!("Failed to configure firewall rules: {}", e);
                 return;
             }
 
@@ -282,91 +677,318 @@ async fn main() {
                 "Starting server on {}:{}",
                 config.server.host, config.server.port
             );
-            start_daemon(ipset, addr, cli.config.clone(), cli.dry_run).await;
+            let pidfile = PidFile::new(config.pidfile());
+            if let Err(e) = pidfile.write() {
+                warn!(
+                    "Failed to write pidfile {}: {}",
+                    pidfile.path().display(),
+                    e
+                );
+            }
+            start_daemon(backend.clone(), addr, cli.config.clone()).await;
+
+            if config.teardown_on_stop {
+                if let Err(e) = backend::run(&backend, |backend| backend.teardown()).await {
+                    error!("Failed to tear down firewall: {}", e);
+                }
+            }
+            pidfile.remove();
         }
-        Commands::Stop => {
-            println!("Stopping daemon (not implemented)");
+        Commands::Stop => exit(stop_daemon(&config, cli.dry_run).await),
+        Commands::Status => exit(show_status(&config).await),
+        Commands::Teardown => {
+            // A running daemon would put its sets back on the next list refresh
+            if let Some(pid) = PidFile::new(config.pidfile()).running_pid() {
+                error!("eidamon is running (pid {}), stop it first", pid);
+                exit(1);
+            }
+
+            if let Err(e) = backend::run(&backend, |backend| backend.teardown()).await {
+                error!("Failed to tear down firewall: {}", e);
+                exit(1);
+            }
+            info!("Removed all ei chains and sets");
         }
-        Commands::Status => {
-            println!("Checking status (not implemented)");
+        Commands::Plan { json } => {
+            let plan = match plan_changes(&config, &backend).await {
+                Ok(plan) => plan,
+                Err(e) => {
+                    error!("Failed to plan changes: {}", e);
+                    exit(1);
+                }
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&plan).unwrap());
+            } else {
+                print!("{}", plan.render());
+            }
         }
     }
 }
 
-async fn start_daemon(
-    ipset: Arc<RwLock<IpsetController>>,
-    addr: std::net::SocketAddr,
-    config_path: PathBuf,
-    dry_run: bool,
-) {
+/// Ask the running daemon to shut down and wait for it to exit.
+async fn stop_daemon(config: &Config, dry_run: bool) -> i32 {
+    let pidfile = PidFile::new(config.pidfile());
+    let Some(pid) = pidfile.running_pid() else {
+        println!("eidamon is not running");
+        return 0;
+    };
+
+    let mut kill = CmdBuilder::new("kill").with_dry_run(dry_run);
+    if let Err(e) = kill.args(["-TERM", &pid.to_string()]).execute() {
+        error!("Failed to signal eidamon (pid {}): {}", pid, e);
+        return 1;
+    }
+    if dry_run {
+        return 0;
+    }
+
+    for _ in 0..100 {
+        if !pidfile::is_running(pid) {
+            println!("eidamon stopped (pid {})", pid);
+            return 0;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    error!("eidamon (pid {}) did not exit within 10s", pid);
+    1
+}
+
+/// Print what the running daemon reports about itself. Exits 3 when it is not
+/// running and 1 when it is unreachable or its last reload failed.
+async fn show_status(config: &Config) -> i32 {
+    let Some(pid) = PidFile::new(config.pidfile()).running_pid() else {
+        println!("eidamon is not running");
+        return 3;
+    };
+
+    // A wildcard listen address is still reachable through loopback, an IPv6
+    // address needs brackets in the URL
+    let url = match config.server.host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) if ip.is_unspecified() => {
+            format!("http://127.0.0.1:{}/status", config.server.port)
+        }
+        Ok(IpAddr::V6(ip)) if ip.is_unspecified() => {
+            format!("http://[::1]:{}/status", config.server.port)
+        }
+        Ok(ip) => format!("http://{}/status", SocketAddr::new(ip, config.server.port)),
+        Err(_) => format!(
+            "http://{}:{}/status",
+            config.server.host, config.server.port
+        ),
+    };
+
+    let status: StatusResponse = match reqwest::get(&url).await {
+        Ok(response) => match response.json().await {
+            Ok(status) => status,
+            Err(e) => {
+                println!(
+                    "eidamon is running (pid {}) but returned an invalid status: {}",
+                    pid, e
+                );
+                return 1;
+            }
+        },
+        Err(e) => {
+            println!(
+                "eidamon is running (pid {}) but {} is unreachable: {}",
+                pid, url, e
+            );
+            return 1;
+        }
+    };
+
+    println!("eidamon is running (pid {})", status.pid);
+    println!("  config:      {}", status.config_path.display());
+    println!("  listen:      {}", status.listen);
+    if let Some(backend) = status.backend {
+        println!("  backend:     {:?}", backend);
+    }
+    if let Some(features) = &status.features {
+        let mut enabled = Vec::new();
+        if features.portforward {
+            enabled.push("portforward".to_string());
+        }
+        if features.block_badtcp {
+            enabled.push("block_badtcp".to_string());
+        }
+        if status.docker {
+            enabled.push("docker".to_string());
+        }
+        for interface in &status.interfaces {
+            enabled.push(format!("block:{}", interface));
+        }
+        println!("  features:    {}", enabled.join(", "));
+    }
+
+    let healthy = match &status.last_reload {
+        Some(reload) if reload.success => {
+            println!(
+                "  last reload: ok at {} ({:.2}s)",
+                reload.timestamp, reload.duration_secs
+            );
+            true
+        }
+        Some(reload) => {
+            println!(
+                "  last reload: failed at {}: {}",
+                reload.timestamp,
+                reload.error.as_deref().unwrap_or("unknown error")
+            );
+            false
+        }
+        None => {
+            println!("  last reload: none yet");
+            false
+        }
+    };
+
+    if !status.sets.is_empty() {
+        println!("  sets:");
+        for (name, size) in &status.sets {
+            println!("    {:<32} {}", name, size);
+        }
+    }
+
+    if healthy {
+        0
+    } else {
+        1
+    }
+}
+
+/// Resolves once the daemon is asked to stop, by SIGTERM or Ctrl-C.
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("Failed to install SIGTERM handler");
+
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    info!("Shutting down");
+}
+
+async fn start_daemon(backend: SharedBackend, addr: SocketAddr, config_path: PathBuf) {
+    let (queue, worker) = apply::channel();
     let state = AppState {
-        ipset: ipset.clone(),
+        backend: backend.clone(),
         config_path,
-        dry_run,
+        refresh_tasks: Arc::new(Mutex::new(Vec::new())),
+        list_status: Arc::new(RwLock::new(HashMap::new())),
+        metrics: Arc::new(Metrics::new()),
+        listen: addr,
+        applied: Arc::new(RwLock::new(None)),
+        pending: Arc::new(Mutex::new(None)),
+        queue,
     };
 
-    if let Err(e) = load_and_configure(&state).await {
+    let reload_state = state.clone();
+    worker.spawn(move || {
+        let state = reload_state.clone();
+        Box::pin(async move {
+            load_and_configure(&state)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    });
+
+    if let Err(e) = state.queue.reload().await {
         error!("Failed to apply initial configuration: {}", e);
         return;
     }
 
-    let ipset_clone = ipset.clone();
-
     // GET /metrics endpoint
     let metrics = warp::path!("metrics")
         .and(warp::get())
-        .map(|| "Metrics placeholder");
+        .and(with_state(state.clone()))
+        .and_then(export_metrics);
 
     // GET /ports endpoint
     let get_ports = warp::path!("ports")
         .and(warp::get())
-        .and(with_ipset(ipset.clone()))
+        .and(with_backend(backend.clone()))
         .and_then(list_ports);
 
     // PUT /ports endpoint
     let put_ports = warp::path!("ports")
         .and(warp::put())
         .and(warp::body::json())
-        .and(with_ipset(ipset.clone()))
+        .and(with_state(state.clone()))
         .and_then(add_port);
 
     // DELETE /ports endpoint
     let delete_ports = warp::path!("ports")
         .and(warp::delete())
         .and(warp::body::json())
-        .and(with_ipset(ipset_clone))
+        .and(with_state(state.clone()))
         .and_then(remove_port);
 
+    // GET /iplists endpoint
+    let list_status = state.list_status.clone();
+    let get_iplists = warp::path!("iplists")
+        .and(warp::get())
+        .and(warp::any().map(move || list_status.clone()))
+        .and_then(list_iplists);
+
+    // GET /status endpoint
+    let status = warp::path!("status")
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(get_status);
+
     // POST /reload endpoint
     let reload = warp::path!("reload")
         .and(warp::post())
         .and(with_state(state.clone()))
         .and_then(reload_config);
 
+    // POST /apply endpoint
+    let apply = warp::path!("apply")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(apply_config_handler);
+
+    // POST /confirm endpoint
+    let confirm = warp::path!("confirm")
+        .and(warp::post())
+        .and(with_state(state.clone()))
+        .and_then(confirm_apply);
+
     let routes = metrics
         .or(get_ports)
         .or(put_ports)
         .or(delete_ports)
+        .or(get_iplists)
+        .or(status)
         .or(reload)
+        .or(apply)
+        .or(confirm)
         .with(warp::cors().allow_any_origin());
 
-    warp::serve(routes).run(addr).await;
+    let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(addr, shutdown_signal());
+    server.await;
+
+    for task in state.refresh_tasks.lock().unwrap().drain(..) {
+        task.abort();
+    }
 }
 
-fn with_ipset(
-    ipset: Arc<RwLock<IpsetController>>,
-) -> impl Filter<Extract = (Arc<RwLock<IpsetController>>,), Error = Infallible> + Clone {
-    warp::any().map(move || ipset.clone())
+fn with_backend(
+    backend: SharedBackend,
+) -> impl Filter<Extract = (SharedBackend,), Error = Infallible> + Clone {
+    warp::any().map(move || backend.clone())
 }
 
 fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Infallible> + Clone {
     warp::any().map(move || state.clone())
 }
 
-async fn add_port(
-    port: Port,
-    ipset: Arc<RwLock<IpsetController>>,
-) -> Result<impl warp::Reply, Infallible> {
+async fn add_port(port: Port, state: AppState) -> Result<impl warp::Reply, Infallible> {
     let protocol = match Protocol::try_from(port.protocol) {
         Ok(proto) => proto,
         Err(e) => {
@@ -376,8 +998,21 @@ async fn add_port(
             ))
         }
     };
+    if let Err(e) = state.backend.read().await.check_runtime_port(protocol) {
+        return Ok(warp::reply::with_status(
+            e.to_string(),
+            warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+    }
 
-    match ipset.write().unwrap().add_port(port.number, protocol) {
+    let number = port.number;
+    let backend = state.backend.clone();
+    let change = async move {
+        backend::run(&backend, move |backend| backend.add_port(number, protocol))
+            .await
+            .map_err(|e| e.to_string())
+    };
+    match state.queue.run(change).await {
         Ok(_) => Ok(warp::reply::with_status(
             format!("Added port {}/{}", port.number, protocol.to_string()),
             warp::http::StatusCode::OK,
@@ -389,10 +1024,7 @@ async fn add_port(
     }
 }
 
-async fn remove_port(
-    port: Port,
-    ipset: Arc<RwLock<IpsetController>>,
-) -> Result<impl warp::Reply, Infallible> {
+async fn remove_port(port: Port, state: AppState) -> Result<impl warp::Reply, Infallible> {
     let protocol = match Protocol::try_from(port.protocol) {
         Ok(proto) => proto,
         Err(e) => {
@@ -402,8 +1034,23 @@ async fn remove_port(
             ))
         }
     };
+    if let Err(e) = state.backend.read().await.check_runtime_port(protocol) {
+        return Ok(warp::reply::with_status(
+            e.to_string(),
+            warp::http::StatusCode::UNPROCESSABLE_ENTITY,
+        ));
+    }
 
-    match ipset.write().unwrap().remove_port(port.number, protocol) {
+    let number = port.number;
+    let backend = state.backend.clone();
+    let change = async move {
+        backend::run(&backend, move |backend| {
+            backend.remove_port(number, protocol)
+        })
+        .await
+        .map_err(|e| e.to_string())
+    };
+    match state.queue.run(change).await {
         Ok(_) => Ok(warp::reply::with_status(
             format!("Removed port {}/{}", port.number, protocol.to_string()),
             warp::http::StatusCode::OK,
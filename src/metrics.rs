@@ -0,0 +1,268 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::auto::IpListStatus;
+use crate::backend::RuleCounter;
+
+#[derive(Debug, Default)]
+struct ReloadStats {
+    total: u64,
+    failures: u64,
+    duration_sum: Duration,
+    last_duration: Duration,
+    last_timestamp: u64,
+    last_success: bool,
+    last_error: Option<String>,
+}
+
+/// Outcome of the most recent configuration apply, as reported by `GET /status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReloadSummary {
+    pub timestamp: u64,
+    pub duration_secs: f64,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Name, help text and value of a per-list gauge.
+type ListMetric = (&'static str, &'static str, fn(&IpListStatus) -> f64);
+
+/// Daemon-side counters that are not kept by the kernel.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    reloads: Mutex<ReloadStats>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn record_reload(&self, duration: Duration, error: Option<String>) {
+        let mut stats = self.reloads.lock().unwrap();
+        stats.total += 1;
+        if error.is_some() {
+            stats.failures += 1;
+        }
+        stats.duration_sum += duration;
+        stats.last_duration = duration;
+        stats.last_success = error.is_none();
+        stats.last_error = error;
+        stats.last_timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+    }
+
+    /// `None` until the first apply has finished.
+    pub fn last_reload(&self) -> Option<ReloadSummary> {
+        let stats = self.reloads.lock().unwrap();
+        (stats.total > 0).then(|| ReloadSummary {
+            timestamp: stats.last_timestamp,
+            duration_secs: stats.last_duration.as_secs_f64(),
+            success: stats.last_success,
+            error: stats.last_error.clone(),
+        })
+    }
+
+    /// Render everything in the Prometheus text exposition format.
+    pub fn render(
+        &self,
+        counters: &[RuleCounter],
+        set_sizes: &[(String, usize)],
+        lists: &HashMap<String, IpListStatus>,
+    ) -> String {
+        let mut out = String::new();
+
+        // Per-rule counters, straight from the kernel
+        header(
+            &mut out,
+            "ei_rule_packets_total",
+            "counter",
+            "Packets matched by a rule in an ei chain.",
+        );
+        for c in counters {
+            writeln!(out, "ei_rule_packets_total{} {}", rule_labels(c), c.packets).unwrap();
+        }
+        header(
+            &mut out,
+            "ei_rule_bytes_total",
+            "counter",
+            "Bytes matched by a rule in an ei chain.",
+        );
+        for c in counters {
+            writeln!(out, "ei_rule_bytes_total{} {}", rule_labels(c), c.bytes).unwrap();
+        }
+
+        // Per-chain totals are the sum of the rules in the chain
+        let mut chains: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for c in counters {
+            let labels = format!("{{family=\"{}\",chain=\"{}\"}}", c.family, escape(&c.chain));
+            let total = chains.entry(labels).or_default();
+            total.0 += c.packets;
+            total.1 += c.bytes;
+        }
+        header(
+            &mut out,
+            "ei_chain_packets_total",
+            "counter",
+            "Packets matched by any rule of an ei chain.",
+        );
+        for (labels, (packets, _)) in &chains {
+            writeln!(out, "ei_chain_packets_total{} {}", labels, packets).unwrap();
+        }
+        header(
+            &mut out,
+            "ei_chain_bytes_total",
+            "counter",
+            "Bytes matched by any rule of an ei chain.",
+        );
+        for (labels, (_, bytes)) in &chains {
+            writeln!(out, "ei_chain_bytes_total{} {}", labels, bytes).unwrap();
+        }
+
+        header(
+            &mut out,
+            "ei_set_entries",
+            "gauge",
+            "Number of members of an ei set.",
+        );
+        for (name, size) in set_sizes {
+            writeln!(out, "ei_set_entries{{set=\"{}\"}} {}", escape(name), size).unwrap();
+        }
+
+        // IP list fetches
+        let mut lists: Vec<(&String, &IpListStatus)> = lists.iter().collect();
+        lists.sort_by_key(|(name, _)| *name);
+        let list_metrics: [ListMetric; 5] = [
+            (
+                "ei_iplist_last_fetch_timestamp_seconds",
+                "Unix time the live contents of an IP list were fetched.",
+                |s| s.fetched_at as f64,
+            ),
+            (
+                "ei_iplist_last_attempt_timestamp_seconds",
+                "Unix time of the last update attempt of an IP list.",
+                |s| s.last_attempt as f64,
+            ),
+            (
+                "ei_iplist_last_duration_seconds",
+                "Duration of the last update attempt of an IP list.",
+                |s| s.last_duration.as_secs_f64(),
+            ),
+            (
+                "ei_iplist_last_success",
+                "Whether the last update attempt of an IP list succeeded.",
+                |s| s.last_success as u8 as f64,
+            ),
+            (
+                "ei_iplist_from_cache",
+                "Whether an IP list was loaded from the on-disk cache.",
+                |s| s.from_cache as u8 as f64,
+            ),
+        ];
+        for (name, help, value) in list_metrics {
+            header(&mut out, name, "gauge", help);
+            for (list, status) in &lists {
+                writeln!(
+                    out,
+                    "{}{{list=\"{}\"}} {}",
+                    name,
+                    escape(list),
+                    value(status)
+                )
+                .unwrap();
+            }
+        }
+
+        // Reloads
+        let stats = self.reloads.lock().unwrap();
+        header(
+            &mut out,
+            "ei_reloads_total",
+            "counter",
+            "Configuration applies, including the initial one.",
+        );
+        writeln!(out, "ei_reloads_total {}", stats.total).unwrap();
+        header(
+            &mut out,
+            "ei_reload_failures_total",
+            "counter",
+            "Configuration applies that failed.",
+        );
+        writeln!(out, "ei_reload_failures_total {}", stats.failures).unwrap();
+        header(
+            &mut out,
+            "ei_reload_duration_seconds",
+            "summary",
+            "Time spent applying the configuration.",
+        );
+        writeln!(
+            out,
+            "ei_reload_duration_seconds_sum {}",
+            stats.duration_sum.as_secs_f64()
+        )
+        .unwrap();
+        writeln!(out, "ei_reload_duration_seconds_count {}", stats.total).unwrap();
+        header(
+            &mut out,
+            "ei_last_reload_duration_seconds",
+            "gauge",
+            "Duration of the most recent configuration apply.",
+        );
+        writeln!(
+            out,
+            "ei_last_reload_duration_seconds {}",
+            stats.last_duration.as_secs_f64()
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "ei_last_reload_timestamp_seconds",
+            "gauge",
+            "Unix time of the most recent configuration apply.",
+        );
+        writeln!(
+            out,
+            "ei_last_reload_timestamp_seconds {}",
+            stats.last_timestamp
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "ei_last_reload_success",
+            "gauge",
+            "Whether the most recent configuration apply succeeded.",
+        );
+        writeln!(out, "ei_last_reload_success {}", stats.last_success as u8).unwrap();
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn rule_labels(counter: &RuleCounter) -> String {
+    format!(
+        "{{family=\"{}\",chain=\"{}\",position=\"{}\",target=\"{}\",rule=\"{}\"}}",
+        counter.family,
+        escape(&counter.chain),
+        counter.position,
+        escape(&counter.target),
+        escape(&counter.rule)
+    )
+}
+
+/// Escape a label value as required by the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,770 @@
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use crate::backend::{Family, FirewallBackend, RuleCounter, Snapshot};
+use crate::cmd::CmdBuilder;
+use crate::config::{BaselineConfig, Config, FinalAction};
+use crate::error::{Error, Result};
+use crate::guard;
+use crate::plan::FirewallState;
+use crate::rules::{PortRange, PortRule, Protocol, Rule, RuleParser, Source};
+
+const TABLE: &str = "inet ei";
+
+/// Renders the ei chains as a single `inet ei` table and applies every change
+/// as one `nft -f` transaction. Sets live in the same table instead of ipset.
+pub struct NftablesController {
+    cmd: CmdBuilder,
+    whitelist_sets: HashSet<String>,
+    blacklist_sets: HashSet<String>,
+    /// Where the SSH guard looks for sessions
+    proc_root: PathBuf,
+}
+
+impl NftablesController {
+    pub fn new(dry_run: bool) -> Self {
+        NftablesController {
+            cmd: CmdBuilder::new("nft").with_dry_run(dry_run),
+            whitelist_sets: HashSet::new(),
+            blacklist_sets: HashSet::new(),
+            proc_root: PathBuf::from("/proc"),
+        }
+    }
+
+    fn execute(&self, args: &[&str]) -> Result<String> {
+        let mut cmd = self.cmd.clone();
+        cmd.args(args).execute()
+    }
+
+    fn apply(&self, script: &str) -> Result<()> {
+        let mut cmd = self.cmd.clone();
+        cmd.args(["-f", "-"]).execute_with_input(script)?;
+        Ok(())
+    }
+
+    fn port_set_name(protocol: Protocol) -> String {
+        format!("ei-allowed-{}-ports", protocol.to_string())
+    }
+
+    /// Declare a regular chain and empty it, so it can be re-rendered from scratch.
+    fn reset_chain(script: &mut String, chain: &str) {
+        writeln!(script, "add chain {} {}", TABLE, chain).unwrap();
+        writeln!(script, "flush chain {} {}", TABLE, chain).unwrap();
+    }
+
+    /// The `input` and `forward` base chains that feed traffic into `ei`.
+    fn render_base_chains(script: &mut String, docker: bool) {
+        writeln!(script, "add table {}", TABLE).unwrap();
+        for hook in ["input", "forward"] {
+            writeln!(
+                script,
+                "add chain {} {} {{ type filter hook {} priority filter; policy accept; }}",
+                TABLE, hook, hook
+            )
+            .unwrap();
+            writeln!(script, "flush chain {} {}", TABLE, hook).unwrap();
+        }
+        writeln!(script, "add chain {} ei", TABLE).unwrap();
+
+        if docker {
+            writeln!(script, "add chain {} ei-docker", TABLE).unwrap();
+            writeln!(script, "add rule {} forward counter jump ei-docker", TABLE).unwrap();
+        }
+
+        for hook in ["input", "forward"] {
+            writeln!(script, "add rule {} {} counter jump ei", TABLE, hook).unwrap();
+            writeln!(
+                script,
+                "add rule {} {} iifname \"lo\" counter accept",
+                TABLE, hook
+            )
+            .unwrap();
+        }
+    }
+
+    fn render_list_matches(
+        script: &mut String,
+        chain: &str,
+        sets: &HashSet<String>,
+        verdict: &str,
+    ) {
+        let mut names: Vec<&String> = sets.iter().collect();
+        names.sort();
+        for name in names {
+            writeln!(
+                script,
+                "add rule {} {} ip saddr @ei-{}-ipv4 counter {}",
+                TABLE, chain, name, verdict
+            )
+            .unwrap();
+            writeln!(
+                script,
+                "add rule {} {} ip6 saddr @ei-{}-ipv6 counter {}",
+                TABLE, chain, name, verdict
+            )
+            .unwrap();
+        }
+    }
+
+    /// Fill the set of the list's `ip:` rules and match on it, per family.
+    fn render_net_matches(script: &mut String, list: &str, rules: &[Rule], verdict: &str) {
+        for (family, set_type, ip) in [
+            (Family::V4, "ipv4_addr", "ip"),
+            (Family::V6, "ipv6_addr", "ip6"),
+        ] {
+            let networks = RuleParser::get_networks(rules, family);
+            if networks.is_empty() {
+                continue;
+            }
+
+            let set_name = format!("ei-{}-net-{}", list, family.label());
+            writeln!(
+                script,
+                "add set {} {} {{ type {}; flags interval; auto-merge; }}",
+                TABLE, set_name, set_type
+            )
+            .unwrap();
+            writeln!(script, "flush set {} {}", TABLE, set_name).unwrap();
+            writeln!(
+                script,
+                "add element {} {} {{ {} }}",
+                TABLE,
+                set_name,
+                networks.join(", ")
+            )
+            .unwrap();
+            writeln!(
+                script,
+                "add rule {} ei-{} {} saddr @{} counter {}",
+                TABLE, list, ip, set_name, verdict
+            )
+            .unwrap();
+        }
+    }
+
+    /// Declare the sets of the lists compound rules match on, without touching
+    /// their contents. On start the ruleset is applied before any list is fetched.
+    fn render_source_list_sets(script: &mut String, rule_parser: &RuleParser) {
+        let mut declared = HashSet::new();
+        for list in rule_parser.get_source_lists() {
+            if !declared.insert(&list.name) {
+                continue;
+            }
+            for (family, set_type) in [("ipv4", "ipv4_addr"), ("ipv6", "ipv6_addr")] {
+                writeln!(
+                    script,
+                    "add set {} ei-{}-{} {{ type {}; flags interval; auto-merge; }}",
+                    TABLE, list.name, family, set_type
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// One rule per compound rule, matching the port and the source together.
+    fn render_port_from_rules(script: &mut String, chain: &str, rules: &[Rule], verdict: &str) {
+        for (rule, protocol) in RuleParser::get_port_from_rules(rules).flat_map(|rule| {
+            rule.port
+                .protocol
+                .expand()
+                .iter()
+                .map(move |protocol| (rule, protocol))
+        }) {
+            let port = format!("{} dport {}", protocol.to_string(), rule.port.ports);
+            let sources = match &rule.source {
+                Source::Ip(ip) => {
+                    let family = match ip.family() {
+                        Family::V4 => "ip",
+                        Family::V6 => "ip6",
+                    };
+                    vec![format!("{} saddr {}", family, ip)]
+                }
+                Source::IpList(list) => vec![
+                    format!("ip saddr @ei-{}-ipv4", list.name),
+                    format!("ip6 saddr @ei-{}-ipv6", list.name),
+                ],
+                // Sets of the ipset tool aren't visible to nft
+                Source::IpSet(_) => Vec::new(),
+            };
+            for source in sources {
+                writeln!(
+                    script,
+                    "add rule {} {} {} {} counter {}",
+                    TABLE, chain, source, port, verdict
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    fn render_icmp_matches(script: &mut String, chain: &str, rules: &[Rule], verdict: &str) {
+        for (family, icmp) in [(Family::V4, "icmp"), (Family::V6, "icmpv6")] {
+            for icmp_type in RuleParser::get_icmp_types(rules, family) {
+                writeln!(
+                    script,
+                    "add rule {} {} {} type {} counter {}",
+                    TABLE, chain, icmp, icmp_type, verdict
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    fn render_port_matches(script: &mut String, chain: &str, prefix: &str, verdict: &str) {
+        for proto in Protocol::PORTS {
+            let proto = proto.to_string();
+            writeln!(
+                script,
+                "add rule {} {} {} dport @{}-{} counter {}",
+                TABLE, chain, proto, prefix, proto, verdict
+            )
+            .unwrap();
+        }
+    }
+
+    /// Refill the port sets of a list, flushed first so removed ports go with the
+    /// same transaction.
+    fn render_port_elements(script: &mut String, rules: &[Rule], prefix: &str) {
+        for proto in Protocol::PORTS {
+            writeln!(
+                script,
+                "flush set {} {}-{}",
+                TABLE,
+                prefix,
+                proto.to_string()
+            )
+            .unwrap();
+        }
+        for rule in rules {
+            if let Rule::Port(PortRule { ports, protocol }) = rule {
+                for protocol in protocol.expand() {
+                    writeln!(
+                        script,
+                        "add element {} {}-{} {{ {} }}",
+                        TABLE,
+                        prefix,
+                        protocol.to_string(),
+                        ports
+                    )
+                    .unwrap();
+                }
+            }
+        }
+    }
+
+    fn render_badtcp(script: &mut String, check_mss: bool) {
+        Self::reset_chain(script, "ei-badtcp");
+        let mut drop = |rule: &str| {
+            writeln!(script, "add rule {} ei-badtcp {} counter drop", TABLE, rule).unwrap();
+        };
+
+        drop("ct state invalid");
+        for (mask, comp) in [
+            ("fin|syn|rst|psh|ack|urg", "0x0"),
+            ("fin|syn|rst|psh|ack|urg", "fin|psh|urg"),
+            ("fin|syn|rst|psh|ack|urg", "fin|syn|rst|psh|ack|urg"),
+            ("fin|syn", "fin|syn"),
+            ("syn|rst", "syn|rst"),
+            ("fin|rst", "fin|rst"),
+            ("fin|ack", "fin"),
+            ("psh|ack", "psh"),
+            ("ack|urg", "urg"),
+        ] {
+            drop(&format!("tcp flags & ({}) == {}", mask, comp));
+        }
+
+        // A new connection has to start with a bare SYN
+        drop("tcp flags & (fin|syn|rst|ack) != syn ct state new");
+
+        // TCP segments are small enough to never need fragmenting
+        drop("ip frag-off & 0x1fff != 0 meta l4proto tcp");
+        drop("exthdr frag exists meta l4proto tcp");
+
+        if check_mss {
+            // The smallest MSS the minimum MTU of each family allows
+            drop("meta nfproto ipv4 tcp flags & (syn|ack) == syn tcp option maxseg size < 536");
+            drop("meta nfproto ipv6 tcp flags & (syn|ack) == syn tcp option maxseg size < 1220");
+            // As `-m tcpmss ! --mss` does, a SYN without the option is dropped too
+            drop("tcp flags & (syn|ack) == syn tcp option maxseg missing");
+        }
+
+        writeln!(script, "add rule {} ei counter jump ei-badtcp", TABLE).unwrap();
+    }
+
+    fn render_ruleset(&self, config: &Config, rule_parser: &RuleParser) -> String {
+        let mut script = String::new();
+        Self::render_base_chains(&mut script, config.docker);
+        Self::render_source_list_sets(&mut script, rule_parser);
+        Self::reset_chain(&mut script, "ei");
+
+        // Malformed packets go before anything gets a chance to accept them,
+        // established connections included
+        if config.features.block_badtcp {
+            Self::render_badtcp(&mut script, config.features.badtcp_check_mss);
+        }
+
+        if config.baseline.accept_established {
+            writeln!(
+                script,
+                "add rule {} ei ct state established,related counter accept",
+                TABLE
+            )
+            .unwrap();
+        }
+
+        // Whitelist (highest priority)
+        Self::reset_chain(&mut script, "ei-whitelist");
+        Self::render_port_elements(
+            &mut script,
+            rule_parser.get_whitelist_rules(),
+            "ei-whitelist",
+        );
+        Self::render_list_matches(&mut script, "ei-whitelist", &self.whitelist_sets, "accept");
+        Self::render_net_matches(
+            &mut script,
+            "whitelist",
+            rule_parser.get_whitelist_rules(),
+            "accept",
+        );
+        Self::render_port_from_rules(
+            &mut script,
+            "ei-whitelist",
+            rule_parser.get_whitelist_rules(),
+            "accept",
+        );
+        Self::render_icmp_matches(
+            &mut script,
+            "ei-whitelist",
+            rule_parser.get_whitelist_rules(),
+            "accept",
+        );
+        Self::render_port_matches(&mut script, "ei-whitelist", "ei-whitelist", "accept");
+        writeln!(script, "add rule {} ei counter jump ei-whitelist", TABLE).unwrap();
+
+        // Live SSH sessions must survive whatever the blacklists say
+        if config.ssh_guard.enabled {
+            Self::reset_chain(&mut script, "ei-guard");
+            for session in guard::ssh_sessions(&self.proc_root) {
+                info!(
+                    "Guarding SSH session from {} port {} to port {}",
+                    session.remote, session.remote_port, session.local_port
+                );
+                let ip = match session.family() {
+                    Family::V4 => "ip",
+                    Family::V6 => "ip6",
+                };
+                writeln!(
+                    script,
+                    "add rule {} ei-guard {} saddr {} tcp sport {} tcp dport {} ct state established counter accept",
+                    TABLE, ip, session.remote, session.remote_port, session.local_port
+                )
+                .unwrap();
+            }
+            writeln!(script, "add rule {} ei counter jump ei-guard", TABLE).unwrap();
+        }
+
+        // Blacklist
+        Self::reset_chain(&mut script, "ei-blacklist");
+        Self::render_port_elements(
+            &mut script,
+            rule_parser.get_blacklist_rules(),
+            "ei-blacklist",
+        );
+        Self::render_list_matches(&mut script, "ei-blacklist", &self.blacklist_sets, "drop");
+        Self::render_net_matches(
+            &mut script,
+            "blacklist",
+            rule_parser.get_blacklist_rules(),
+            "drop",
+        );
+        Self::render_port_from_rules(
+            &mut script,
+            "ei-blacklist",
+            rule_parser.get_blacklist_rules(),
+            "drop",
+        );
+        Self::render_icmp_matches(
+            &mut script,
+            "ei-blacklist",
+            rule_parser.get_blacklist_rules(),
+            "drop",
+        );
+        Self::render_port_matches(&mut script, "ei-blacklist", "ei-blacklist", "drop");
+        writeln!(script, "add rule {} ei counter jump ei-blacklist", TABLE).unwrap();
+
+        if config.features.portforward {
+            Self::reset_chain(&mut script, "ei-ports");
+            for proto in Protocol::PORTS {
+                writeln!(
+                    script,
+                    "add rule {} ei-ports {} dport @{} counter accept",
+                    TABLE,
+                    proto.to_string(),
+                    Self::port_set_name(proto)
+                )
+                .unwrap();
+            }
+            writeln!(script, "add rule {} ei counter jump ei-ports", TABLE).unwrap();
+        }
+
+        if config.docker {
+            Self::reset_chain(&mut script, "ei-docker");
+            writeln!(
+                script,
+                "add rule {} ei-docker counter jump ei-blacklist",
+                TABLE
+            )
+            .unwrap();
+        }
+
+        for interface in &config.interfaces {
+            writeln!(
+                script,
+                "add rule {} ei iifname \"{}\" counter drop",
+                TABLE, interface
+            )
+            .unwrap();
+        }
+
+        // The last rule of `ei`, commented with the configured action
+        let baseline = &config.baseline;
+        let verdict = match baseline.final_action {
+            FinalAction::Drop => "drop",
+            FinalAction::Reject => "reject",
+            FinalAction::Return => "return",
+        };
+        let matches: Vec<String> = if baseline.wan_interfaces.is_empty() {
+            vec!["iifname != \"lo\"".to_string()]
+        } else {
+            baseline
+                .wan_interfaces
+                .iter()
+                .map(|interface| format!("iifname \"{}\"", interface))
+                .collect()
+        };
+        for matched in matches {
+            writeln!(
+                script,
+                "add rule {} ei {} counter {} comment \"ei final action: {}\"",
+                TABLE,
+                matched,
+                verdict,
+                baseline.final_action.label()
+            )
+            .unwrap();
+        }
+
+        script
+    }
+
+    /// Pull the counters out of `nft list table inet ei`, where each rule is a line
+    /// such as `tcp dport @ei-allowed-tcp-ports counter packets 3 bytes 180 accept`.
+    fn parse_counters(output: &str) -> Vec<RuleCounter> {
+        let mut counters = Vec::new();
+        let mut chain: Option<&str> = None;
+        let mut position = 0;
+
+        for line in output.lines().map(str::trim) {
+            if let Some(name) = line
+                .strip_prefix("chain ")
+                .and_then(|l| l.strip_suffix(" {"))
+            {
+                chain = Some(name);
+                position = 0;
+                continue;
+            }
+            if line == "}" {
+                chain = None;
+                continue;
+            }
+            let Some(chain) = chain else { continue };
+            let Some((matches, rest)) = line.split_once("counter packets ") else {
+                continue;
+            };
+
+            let mut fields = rest.split_whitespace();
+            let packets = fields
+                .next()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or_default();
+            let bytes = fields
+                .nth(1)
+                .and_then(|b| b.parse().ok())
+                .unwrap_or_default();
+            let verdict: Vec<&str> = fields.collect();
+
+            position += 1;
+            counters.push(RuleCounter {
+                family: "inet",
+                chain: chain.to_string(),
+                position,
+                target: verdict.last().map(|v| v.to_string()).unwrap_or_default(),
+                rule: format!("{} {}", matches.trim(), verdict.join(" "))
+                    .trim()
+                    .to_string(),
+                packets,
+                bytes,
+            });
+        }
+
+        counters
+    }
+
+    /// Elements of a port set, either a bare port or `{"range": [first, last]}`.
+    fn parse_set_elements(output: &str) -> Vec<PortRange> {
+        let json: serde_json::Value = match serde_json::from_str(output) {
+            Ok(json) => json,
+            Err(_) => return Vec::new(),
+        };
+
+        let port = |value: &serde_json::Value| u16::try_from(value.as_u64()?).ok();
+        PortRange::merge(
+            json["nftables"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|item| item["set"]["elem"].as_array())
+                .flatten()
+                .filter_map(|elem| match elem["range"].as_array() {
+                    Some(range) => Some(PortRange {
+                        first: port(range.first()?)?,
+                        last: port(range.get(1)?)?,
+                    }),
+                    None => port(elem).map(PortRange::single),
+                }),
+        )
+    }
+}
+
+impl FirewallBackend for NftablesController {
+    fn init(&self, _baseline: &BaselineConfig) -> Result<()> {
+        info!("Initializing nftables table {}", TABLE);
+        let mut script = String::new();
+        Self::render_base_chains(&mut script, false);
+        self.apply(&script)
+    }
+
+    fn init_sets(&self) -> Result<()> {
+        info!("Initializing nftables port sets");
+        let mut script = String::new();
+        writeln!(script, "add table {}", TABLE).unwrap();
+        let lists = ["whitelist", "blacklist"].into_iter().flat_map(|list| {
+            Protocol::PORTS.map(|protocol| format!("ei-{}-{}", list, protocol.to_string()))
+        });
+        for set_name in Protocol::PORTS
+            .map(Self::port_set_name)
+            .into_iter()
+            .chain(lists)
+        {
+            // Intervals hold port ranges
+            writeln!(
+                script,
+                "add set {} {} {{ type inet_service; flags interval; }}",
+                TABLE, set_name
+            )
+            .unwrap();
+        }
+        // Existing sets keep their ports until the apply refills them
+        self.apply(&script)
+    }
+
+    fn configure_with_rules(&self, config: &Config, rule_parser: &RuleParser) -> Result<()> {
+        for rule in rule_parser.get_ipset_rules() {
+            warn!(
+                "ipset '{}' can't be matched by the nftables backend, its rules are left out",
+                rule.name
+            );
+        }
+        info!("Applying nftables ruleset");
+        self.apply(&self.render_ruleset(config, rule_parser))
+    }
+
+    fn configure_port_rules(&self, rules: &[&Rule]) -> Result<()> {
+        info!("Configuring port rules: {:?}", rules);
+        // Flush and refill in one transaction, so ports removed from the config
+        // are closed and the others stay open throughout
+        let mut script = String::new();
+        for protocol in Protocol::PORTS {
+            writeln!(
+                script,
+                "flush set {} {}",
+                TABLE,
+                Self::port_set_name(protocol)
+            )
+            .unwrap();
+        }
+        for rule in rules {
+            if let Rule::Port(PortRule { ports, protocol }) = rule {
+                for protocol in protocol.expand() {
+                    writeln!(
+                        script,
+                        "add element {} {} {{ {} }}",
+                        TABLE,
+                        Self::port_set_name(*protocol),
+                        ports
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        self.apply(&script)
+    }
+
+    fn replace_set(&self, set_name: &str, family: Family, values: &[&str]) -> Result<()> {
+        let set_type = match family {
+            Family::V4 => "ipv4_addr",
+            Family::V6 => "ipv6_addr",
+        };
+
+        // Flush and refill in the same transaction, so the set is never seen empty
+        let mut script = String::new();
+        writeln!(script, "add table {}", TABLE).unwrap();
+        writeln!(
+            script,
+            "add set {} {} {{ type {}; flags interval; auto-merge; }}",
+            TABLE, set_name, set_type
+        )
+        .unwrap();
+        writeln!(script, "flush set {} {}", TABLE, set_name).unwrap();
+        if !values.is_empty() {
+            writeln!(
+                script,
+                "add element {} {} {{ {} }}",
+                TABLE,
+                set_name,
+                values.join(", ")
+            )
+            .unwrap();
+        }
+        self.apply(&script)
+    }
+
+    fn add_to_set(&self, set_name: &str, value: &str) -> Result<()> {
+        self.execute(&["add", "element", "inet", "ei", set_name, "{", value, "}"])?;
+        Ok(())
+    }
+
+    fn list_ports(&self) -> Result<Vec<(PortRange, Protocol)>> {
+        let mut ports = Vec::new();
+
+        for protocol in Protocol::PORTS {
+            if let Ok(output) = self.execute(&[
+                "-j",
+                "list",
+                "set",
+                "inet",
+                "ei",
+                &Self::port_set_name(protocol),
+            ]) {
+                ports.extend(
+                    Self::parse_set_elements(&output)
+                        .into_iter()
+                        .map(|range| (range, protocol)),
+                );
+            }
+        }
+
+        ports.sort_by_key(|(range, _)| *range);
+        Ok(ports)
+    }
+
+    fn add_port(&self, port: u16, protocol: Protocol) -> Result<()> {
+        for protocol in protocol.expand() {
+            self.add_to_set(&Self::port_set_name(*protocol), &port.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn remove_port(&self, port: u16, protocol: Protocol) -> Result<()> {
+        for protocol in protocol.expand() {
+            self.execute(&[
+                "delete",
+                "element",
+                "inet",
+                "ei",
+                &Self::port_set_name(*protocol),
+                "{",
+                &port.to_string(),
+                "}",
+            ])?;
+        }
+        Ok(())
+    }
+
+    fn rule_counters(&self) -> Result<Vec<RuleCounter>> {
+        let output = self.execute(&["list", "table", "inet", "ei"])?;
+        Ok(Self::parse_counters(&output))
+    }
+
+    fn set_sizes(&self) -> Result<Vec<(String, usize)>> {
+        let output = self.execute(&["-j", "list", "table", "inet", "ei"])?;
+        let json: serde_json::Value = serde_json::from_str(&output).unwrap_or_default();
+
+        Ok(json["nftables"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| {
+                let set = item.get("set")?;
+                let name = set["name"].as_str()?.to_string();
+                Some((name, set["elem"].as_array().map_or(0, Vec::len)))
+            })
+            .collect())
+    }
+
+    fn teardown(&self) -> Result<()> {
+        // Chains and sets all live in the table, so it goes in one step
+        if self.execute(&["list", "table", "inet", "ei"]).is_ok() {
+            info!("Deleting table {}", TABLE);
+            self.execute(&["delete", "table", "inet", "ei"])?;
+        }
+        Ok(())
+    }
+
+    fn current_state(&self) -> Result<FirewallState> {
+        Err(Error::CommandFailed(
+            "plan is not supported by the nftables backend yet".to_string(),
+        ))
+    }
+
+    fn desired_state(&self, _config: &Config, _rule_parser: &RuleParser) -> Result<FirewallState> {
+        Err(Error::CommandFailed(
+            "plan is not supported by the nftables backend yet".to_string(),
+        ))
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::Nftables {
+            table: self.execute(&["list", "table", "inet", "ei"]).ok(),
+        })
+    }
+
+    fn rollback(&self, snapshot: &Snapshot) -> Result<()> {
+        let Snapshot::Nftables { table } = snapshot else {
+            return Err(Error::CommandFailed(
+                "snapshot was not taken by the nftables backend".to_string(),
+            ));
+        };
+        // Adding first makes the delete safe when the table is already gone
+        let mut script = String::new();
+        writeln!(script, "add table {}", TABLE).unwrap();
+        writeln!(script, "delete table {}", TABLE).unwrap();
+        if let Some(table) = table {
+            script.push_str(table);
+        }
+        info!("Rolling back table {}", TABLE);
+        self.apply(&script)
+    }
+
+    fn register_whitelist_set(&mut self, name: String) {
+        info!("Registering whitelist set: {}", name);
+        self.whitelist_sets.insert(name);
+    }
+
+    fn register_blacklist_set(&mut self, name: String) {
+        info!("Registering blacklist set: {}", name);
+        self.blacklist_sets.insert(name);
+    }
+}
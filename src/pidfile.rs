@@ -0,0 +1,48 @@
+use log::warn;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Records the pid of the running daemon so `stop` and `status` can find it.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn new(path: PathBuf) -> Self {
+        PidFile { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn read(&self) -> Option<u32> {
+        fs::read_to_string(&self.path).ok()?.trim().parse().ok()
+    }
+
+    /// The recorded pid, if that process is still alive.
+    pub fn running_pid(&self) -> Option<u32> {
+        self.read().filter(|pid| is_running(*pid))
+    }
+
+    pub fn write(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, format!("{}\n", std::process::id()))
+    }
+
+    pub fn remove(&self) {
+        // Only remove our own pidfile, never the one of a daemon started after us
+        if self.read() == Some(std::process::id()) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Failed to remove pidfile {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+pub fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
@@ -0,0 +1,247 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How many members of a set the human output lists before summarizing.
+const MAX_LISTED_MEMBERS: usize = 20;
+
+/// The ei-owned part of the firewall, either as installed or as a config would install it.
+#[derive(Debug, Default)]
+pub struct FirewallState {
+    /// Rules as printed by `iptables-save`, keyed by family label and chain
+    pub rules: BTreeMap<(&'static str, String), Vec<String>>,
+    pub sets: BTreeMap<String, BTreeSet<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Add,
+    Remove,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RuleChange {
+    pub family: &'static str,
+    pub chain: String,
+    pub change: Change,
+    pub rule: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetChange {
+    pub set: String,
+    /// The set doesn't exist yet and would be created
+    pub create: bool,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// What applying the config would change on the live box.
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    pub rules: Vec<RuleChange>,
+    pub sets: Vec<SetChange>,
+    /// List sets whose contents can't be predicted because the list was never cached
+    pub unknown_sets: Vec<String>,
+}
+
+impl Plan {
+    /// Diff two states. Only chains and sets the desired state covers are compared,
+    /// an apply leaves everything else alone.
+    pub fn between(current: &FirewallState, desired: &FirewallState) -> Self {
+        let mut plan = Plan::default();
+
+        for ((family, chain), wanted) in &desired.rules {
+            let installed = current
+                .rules
+                .get(&(*family, chain.clone()))
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+
+            plan.rules
+                .extend(
+                    sequence_diff(installed, wanted)
+                        .into_iter()
+                        .map(|(change, rule)| RuleChange {
+                            family,
+                            chain: chain.clone(),
+                            change,
+                            rule,
+                        }),
+                );
+        }
+
+        for (set, wanted) in &desired.sets {
+            let installed = current.sets.get(set);
+            let empty = BTreeSet::new();
+            let have: BTreeSet<String> = installed
+                .unwrap_or(&empty)
+                .iter()
+                .map(|m| normalize_member(m))
+                .collect();
+            let want: BTreeSet<String> = wanted.iter().map(|m| normalize_member(m)).collect();
+
+            let added: Vec<String> = want.difference(&have).cloned().collect();
+            let removed: Vec<String> = have.difference(&want).cloned().collect();
+            if installed.is_none() || !added.is_empty() || !removed.is_empty() {
+                plan.sets.push(SetChange {
+                    set: set.clone(),
+                    create: installed.is_none(),
+                    added,
+                    removed,
+                });
+            }
+        }
+
+        plan
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.sets.is_empty()
+    }
+
+    /// Render the plan for a terminal, `+` for additions and `-` for removals.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let mut chain = None;
+        for change in &self.rules {
+            if chain != Some((change.family, &change.chain)) {
+                chain = Some((change.family, &change.chain));
+                out.push_str(&format!("{} chain {}:\n", change.family, change.chain));
+            }
+            let sign = match change.change {
+                Change::Add => '+',
+                Change::Remove => '-',
+            };
+            out.push_str(&format!("  {} {}\n", sign, change.rule));
+        }
+
+        for change in &self.sets {
+            let action = if change.create { " (create)" } else { "" };
+            out.push_str(&format!("set {}{}:\n", change.set, action));
+            for (sign, members) in [('+', &change.added), ('-', &change.removed)] {
+                for member in members.iter().take(MAX_LISTED_MEMBERS) {
+                    out.push_str(&format!("  {} {}\n", sign, member));
+                }
+                if members.len() > MAX_LISTED_MEMBERS {
+                    out.push_str(&format!(
+                        "  {} ... and {} more\n",
+                        sign,
+                        members.len() - MAX_LISTED_MEMBERS
+                    ));
+                }
+            }
+        }
+
+        for set in &self.unknown_sets {
+            out.push_str(&format!(
+                "set {}: never fetched, contents not compared\n",
+                set
+            ));
+        }
+
+        if self.is_empty() {
+            out.push_str("No changes.\n");
+        }
+        out
+    }
+}
+
+/// The rules to remove from and add to `current` to get `desired`, in chain
+/// order around their longest common subsequence. Order decides the verdict in
+/// a chain, so a moved rule shows as removed and added again.
+fn sequence_diff(current: &[String], desired: &[String]) -> Vec<(Change, String)> {
+    let (n, m) = (current.len(), desired.len());
+    // common[i][j]: length of the longest common subsequence of current[i..] and desired[j..]
+    let mut common = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if current[i] == desired[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && current[i] == desired[j] {
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || common[i + 1][j] >= common[i][j + 1]) {
+            changes.push((Change::Remove, current[i].clone()));
+            i += 1;
+        } else {
+            changes.push((Change::Add, desired[j].clone()));
+            j += 1;
+        }
+    }
+    changes
+}
+
+/// `ipset list` prints single hosts of a hash:net set without their prefix.
+fn normalize_member(member: &str) -> String {
+    member
+        .strip_suffix("/32")
+        .filter(|addr| !addr.contains(':'))
+        .or_else(|| member.strip_suffix("/128"))
+        .unwrap_or(member)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(chain: &[&str]) -> FirewallState {
+        let mut state = FirewallState::default();
+        state.rules.insert(
+            ("ipv4", "ei".to_string()),
+            chain.iter().map(|rule| rule.to_string()).collect(),
+        );
+        state
+    }
+
+    fn changes(plan: &Plan) -> Vec<(Change, &str)> {
+        plan.rules
+            .iter()
+            .map(|change| (change.change, change.rule.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn reordered_rules_are_a_change() {
+        let current = state(&["-A ei -j ei-blacklist", "-A ei -j ei-whitelist"]);
+        let desired = state(&["-A ei -j ei-whitelist", "-A ei -j ei-blacklist"]);
+
+        let plan = Plan::between(&current, &desired);
+        assert_eq!(
+            changes(&plan),
+            [
+                (Change::Remove, "-A ei -j ei-blacklist"),
+                (Change::Add, "-A ei -j ei-blacklist"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rule_changes_keep_chain_order() {
+        let current = state(&["-A ei -j a", "-A ei -j b", "-A ei -j c", "-A ei -j c"]);
+        let desired = state(&["-A ei -j a", "-A ei -j x", "-A ei -j c", "-A ei -j d"]);
+
+        let plan = Plan::between(&current, &desired);
+        assert_eq!(
+            changes(&plan),
+            [
+                (Change::Remove, "-A ei -j b"),
+                (Change::Remove, "-A ei -j c"),
+                (Change::Add, "-A ei -j x"),
+                (Change::Add, "-A ei -j d"),
+            ]
+        );
+        assert!(Plan::between(&current, &current).is_empty());
+    }
+}
@@ -1,20 +1,220 @@
 use log::error;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 
+use crate::backend::Family;
+
 #[derive(Debug, Clone, Serialize)]
 pub enum Rule {
     Port(PortRule),
+    Ip(IpRule),
     IpList(IpListRule),
     IpSet(IpSetRule),
+    PortFrom(PortFromRule),
+    Icmp(IcmpRule),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PortRule {
-    pub number: u16,
+    pub ports: PortRange,
     pub protocol: Protocol,
 }
 
+impl FromStr for PortRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.len() != 2 {
+            return Err(
+                "Invalid port format. Expected: port/protocol (e.g., 80/tcp or 8000-8100/tcp)"
+                    .to_string(),
+            );
+        }
+
+        let ports = PortRange::from_str(parts[0])?;
+        let protocol = Protocol::from_str(parts[1]).map_err(|_| {
+            format!(
+                "Invalid protocol: '{}'. Protocol must be one of 'tcp', 'udp', 'sctp' or 'any'.",
+                parts[1]
+            )
+        })?;
+
+        Ok(PortRule { ports, protocol })
+    }
+}
+
+/// A port rule that only matches traffic from one source, written
+/// `22/tcp from iplist:office`, `443/tcp from ip:192.0.2.0/24` or
+/// `25/tcp from ipset:relays`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortFromRule {
+    pub port: PortRule,
+    pub source: Source,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Source {
+    Ip(IpRule),
+    IpList(IpListRule),
+    IpSet(IpSetRule),
+}
+
+/// An inclusive block of ports, written `8000-8100`, or a single port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    pub fn single(port: u16) -> Self {
+        PortRange {
+            first: port,
+            last: port,
+        }
+    }
+
+    pub fn ports(&self) -> RangeInclusive<u16> {
+        self.first..=self.last
+    }
+
+    /// Sort `ranges` and join the ones that overlap or touch, so ports listed one
+    /// by one come back as the ranges they were added as.
+    pub fn merge(ranges: impl IntoIterator<Item = PortRange>) -> Vec<PortRange> {
+        let mut ranges: Vec<PortRange> = ranges.into_iter().collect();
+        ranges.sort_unstable();
+
+        let mut merged: Vec<PortRange> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.first <= last.last.saturating_add(1) => {
+                    last.last = last.last.max(range.last);
+                }
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Port sets are `bitmap:port range 1-65535`, 0 can't go in them
+        let port = |value: &str| {
+            value
+                .parse::<u16>()
+                .ok()
+                .filter(|port| *port != 0)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid port number: '{}'. Port must be a valid number between 1 and 65535.",
+                        value
+                    )
+                })
+        };
+
+        match s.split_once('-') {
+            Some((first, last)) => {
+                let range = PortRange {
+                    first: port(first)?,
+                    last: port(last)?,
+                };
+                if range.first > range.last {
+                    return Err(format!(
+                        "Invalid port range: '{}'. The first port must not be above the last.",
+                        s
+                    ));
+                }
+                Ok(range)
+            }
+            None => Ok(PortRange::single(port(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.first == self.last {
+            write!(f, "{}", self.first)
+        } else {
+            write!(f, "{}-{}", self.first, self.last)
+        }
+    }
+}
+
+/// A source address or network, written `ip:192.0.2.0/24` or `ip:2001:db8::1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpRule {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl IpRule {
+    pub fn family(&self) -> Family {
+        match self.addr {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+}
+
+impl FromStr for IpRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("'{}' is not an IPv4 or IPv6 address", addr))?;
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        // hash:net can't hold a /0
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| (1..=max).contains(prefix))
+                .ok_or_else(|| format!("prefix '/{}' must be between 1 and {}", prefix, max))?,
+            None => max,
+        };
+
+        // Host bits past the prefix are almost always a typo for another network
+        let bits = match addr {
+            IpAddr::V4(v4) => u128::from(u32::from(v4)) << 96,
+            IpAddr::V6(v6) => u128::from(v6),
+        };
+        let host_bits = u128::MAX.checked_shr(u32::from(prefix)).unwrap_or(0);
+        if bits & host_bits != 0 {
+            let network = bits & !host_bits;
+            let network = match addr {
+                IpAddr::V4(_) => IpAddr::from(((network >> 96) as u32).to_be_bytes()),
+                IpAddr::V6(_) => IpAddr::from(network.to_be_bytes()),
+            };
+            return Err(format!(
+                "'{}' has host bits set, did you mean {}/{}?",
+                s, network, prefix
+            ));
+        }
+
+        Ok(IpRule { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IpListRule {
     pub name: String,
@@ -30,17 +230,92 @@ pub struct IpSetRule {
 pub enum Protocol {
     TCP,
     UDP,
+    // Spelled like TCP and UDP, the serialized form goes by the variant name
+    #[allow(clippy::upper_case_acronyms)]
+    SCTP,
+    /// Shorthand for TCP and UDP, written `any` or `tcp+udp`
+    Any,
+}
+
+impl Protocol {
+    /// The protocols a port rule can name, `Any` aside.
+    pub const PORTS: [Protocol; 3] = [Protocol::TCP, Protocol::UDP, Protocol::SCTP];
+
+    /// The protocols a rule for `self` covers, `Any` standing for TCP and UDP.
+    pub fn expand(self) -> &'static [Protocol] {
+        match self {
+            Protocol::TCP => &[Protocol::TCP],
+            Protocol::UDP => &[Protocol::UDP],
+            Protocol::SCTP => &[Protocol::SCTP],
+            Protocol::Any => &[Protocol::TCP, Protocol::UDP],
+        }
+    }
 }
 
 impl TryFrom<String> for Protocol {
     type Error = String;
 
     fn try_from(s: String) -> Result<Self, Self::Error> {
-        match s.to_lowercase().as_str() {
-            "tcp" => Ok(Protocol::TCP),
-            "udp" => Ok(Protocol::UDP),
-            _ => Err("Invalid protocol".to_string()),
-        }
+        Protocol::from_str(&s).map_err(|_| "Invalid protocol".to_string())
+    }
+}
+
+/// ICMP types by name, as `icmp:` rules take them.
+const ICMP_TYPES: &[(&str, u8)] = &[
+    ("echo-reply", 0),
+    ("destination-unreachable", 3),
+    ("source-quench", 4),
+    ("redirect", 5),
+    ("echo-request", 8),
+    ("router-advertisement", 9),
+    ("router-solicitation", 10),
+    ("time-exceeded", 11),
+    ("parameter-problem", 12),
+    ("timestamp-request", 13),
+    ("timestamp-reply", 14),
+];
+
+/// ICMPv6 types by name, as `icmpv6:` rules take them.
+const ICMPV6_TYPES: &[(&str, u8)] = &[
+    ("destination-unreachable", 1),
+    ("packet-too-big", 2),
+    ("time-exceeded", 3),
+    ("parameter-problem", 4),
+    ("echo-request", 128),
+    ("echo-reply", 129),
+    ("router-solicitation", 133),
+    ("router-advertisement", 134),
+    ("neighbour-solicitation", 135),
+    ("neighbour-advertisement", 136),
+    ("redirect", 137),
+];
+
+/// An ICMP or ICMPv6 message type, written `icmp:echo-request` or
+/// `icmpv6:packet-too-big`. Types without a name are given as a number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IcmpRule {
+    pub family: Family,
+    pub icmp_type: u8,
+}
+
+impl IcmpRule {
+    fn parse(family: Family, s: &str) -> Result<Self, String> {
+        let types = match family {
+            Family::V4 => ICMP_TYPES,
+            Family::V6 => ICMPV6_TYPES,
+        };
+        let icmp_type = match types.iter().find(|(name, _)| *name == s) {
+            Some((_, number)) => *number,
+            None => s.parse::<u8>().map_err(|_| {
+                let names: Vec<&str> = types.iter().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown type '{}', expected a number or one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })?,
+        };
+        Ok(IcmpRule { family, icmp_type })
     }
 }
 
@@ -48,6 +323,9 @@ impl TryFrom<String> for Protocol {
 pub struct IpListConfig {
     pub urls: IpListUrls,
     pub enabled: bool,
+    /// Seconds between background refreshes; unset to fetch only on start and reload
+    #[serde(default)]
+    pub refresh_interval: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -60,32 +338,41 @@ impl FromStr for Rule {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.contains('/') {
-            // Parse port rule (e.g., "80/tcp")
-            let parts: Vec<&str> = s.split('/').collect();
-            if parts.len() != 2 {
-                return Err(
-                    "Invalid port format. Expected: port/protocol (e.g., 80/tcp)".to_string(),
-                );
-            }
-
-            let port = parts[0].parse::<u16>().map_err(|_| {
-                format!(
-                    "Invalid port number: '{}'. Port must be a valid number between 0 and 65535.",
-                    parts[0]
-                )
-            })?;
-            let protocol = Protocol::from_str(parts[1]).map_err(|_| {
-                format!(
-                    "Invalid protocol: '{}'. Protocol must be one of 'tcp', 'udp', etc.",
-                    parts[1]
-                )
-            })?;
-
-            Ok(Rule::Port(PortRule {
-                number: port,
-                protocol,
-            }))
+        if let Some((port, source)) = s.split_once(" from ") {
+            // Parse compound rule (e.g., "22/tcp from iplist:office"), first as
+            // both halves may contain a slash
+            let port = PortRule::from_str(port.trim())?;
+            let source = match Rule::from_str(source.trim())? {
+                Rule::Ip(ip) => Source::Ip(ip),
+                Rule::IpList(list) => Source::IpList(list),
+                Rule::IpSet(set) => Source::IpSet(set),
+                _ => {
+                    return Err(format!(
+                        "Invalid rule '{}': the source after 'from' must be an ip:, iplist: or ipset: rule",
+                        s
+                    ))
+                }
+            };
+            Ok(Rule::PortFrom(PortFromRule { port, source }))
+        } else if let Some(value) = s.strip_prefix("ip:") {
+            // Parse address rule (e.g., "ip:192.0.2.0/24"), before the port
+            // form below as a CIDR contains a slash too
+            let rule =
+                IpRule::from_str(value).map_err(|e| format!("Invalid ip rule '{}': {}", s, e))?;
+            Ok(Rule::Ip(rule))
+        } else if let Some(value) = s.strip_prefix("icmp:") {
+            // Parse ICMP type rule (e.g., "icmp:echo-request")
+            let rule = IcmpRule::parse(Family::V4, value)
+                .map_err(|e| format!("Invalid icmp rule '{}': {}", s, e))?;
+            Ok(Rule::Icmp(rule))
+        } else if let Some(value) = s.strip_prefix("icmpv6:") {
+            // Parse ICMPv6 type rule (e.g., "icmpv6:echo-request")
+            let rule = IcmpRule::parse(Family::V6, value)
+                .map_err(|e| format!("Invalid icmpv6 rule '{}': {}", s, e))?;
+            Ok(Rule::Icmp(rule))
+        } else if s.contains('/') {
+            // Parse port rule (e.g., "80/tcp" or "8000-8100/tcp")
+            Ok(Rule::Port(PortRule::from_str(s)?))
         } else if s.starts_with("iplist:") {
             // Parse iplist rule (e.g., "iplist:cloudflare")
             if s.len() <= 7 {
@@ -111,9 +398,12 @@ impl FromStr for Rule {
         } else {
             error!(
                 "Invalid rule format: '{}'.\nHint: Valid formats are:\n\
-                - Port rule: port/protocol (e.g., 80/tcp)\n\
+                - Port rule: port/protocol, protocol being tcp, udp, sctp or any (e.g., 80/tcp or 8000-8100/any)\n\
+                - Icmp rule: icmp:<type> or icmpv6:<type> (e.g., icmp:echo-request)\n\
+                - Ip rule: ip:<address or CIDR> (e.g., ip:192.0.2.0/24)\n\
                 - IpList rule: iplist:<name> (e.g., iplist:cloudflare)\n\
-                - IpSet rule: ipset:<name> (e.g., ipset:xcord)",
+                - IpSet rule: ipset:<name> (e.g., ipset:xcord)\n\
+                - Port from a source: <port rule> from <ip, iplist or ipset rule> (e.g., 22/tcp from iplist:office)",
                 s
             );
             Err("Invalid rule format".to_string())
@@ -138,7 +428,9 @@ impl FromStr for Protocol {
         match s.to_lowercase().as_str() {
             "tcp" => Ok(Protocol::TCP),
             "udp" => Ok(Protocol::UDP),
-            _ => Err("Protocol must be tcp or udp".to_string()),
+            "sctp" => Ok(Protocol::SCTP),
+            "any" | "tcp+udp" | "udp+tcp" => Ok(Protocol::Any),
+            _ => Err("Protocol must be tcp, udp, sctp or any".to_string()),
         }
     }
 }
@@ -148,6 +440,8 @@ impl ToString for Protocol {
         match self {
             Protocol::TCP => "tcp".to_string(),
             Protocol::UDP => "udp".to_string(),
+            Protocol::SCTP => "sctp".to_string(),
+            Protocol::Any => "any".to_string(),
         }
     }
 }
@@ -197,8 +491,15 @@ impl RuleParser {
         self.whitelist_rules
             .iter()
             .chain(self.blacklist_rules.iter())
-            .map(|rule| rule.clone())
-            .filter(|rule| matches!(rule, Rule::IpList(_)))
+            .filter_map(|rule| match rule {
+                Rule::IpList(_) => Some(rule.clone()),
+                // Lists only used as a source still have to be fetched
+                Rule::PortFrom(PortFromRule {
+                    source: Source::IpList(list),
+                    ..
+                }) => Some(Rule::IpList(list.clone())),
+                _ => None,
+            })
             .collect()
     }
 
@@ -210,11 +511,200 @@ impl RuleParser {
             .collect()
     }
 
-    // pub fn get_ipset_rules(&self) -> Vec<&Rule> {
-    //     self.whitelist_rules
-    //         .iter()
-    //         .chain(self.blacklist_rules.iter())
-    //         .filter(|rule| matches!(rule, Rule::IpSet(_)))
-    //         .collect()
-    // }
+    /// The `ip:` entries of `rules` for one family, as `hash:net` members.
+    pub fn get_networks(rules: &[Rule], family: Family) -> Vec<String> {
+        rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::Ip(ip) if ip.family() == family => Some(ip.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The `icmp:` and `icmpv6:` entries of `rules` for one family.
+    pub fn get_icmp_types(rules: &[Rule], family: Family) -> Vec<u8> {
+        rules
+            .iter()
+            .filter_map(|rule| match rule {
+                Rule::Icmp(icmp) if icmp.family == family => Some(icmp.icmp_type),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The IP lists compound rules match on as their source.
+    pub fn get_source_lists(&self) -> Vec<&IpListRule> {
+        self.whitelist_rules
+            .iter()
+            .chain(self.blacklist_rules.iter())
+            .filter_map(|rule| match rule {
+                Rule::PortFrom(PortFromRule {
+                    source: Source::IpList(list),
+                    ..
+                }) => Some(list),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The compound rules of `rules`.
+    pub fn get_port_from_rules(rules: &[Rule]) -> impl Iterator<Item = &PortFromRule> {
+        rules.iter().filter_map(|rule| match rule {
+            Rule::PortFrom(rule) => Some(rule),
+            _ => None,
+        })
+    }
+
+    /// The externally managed sets the rules match on, directly or as a source.
+    pub fn get_ipset_rules(&self) -> Vec<&IpSetRule> {
+        self.whitelist_rules
+            .iter()
+            .chain(self.blacklist_rules.iter())
+            .filter_map(|rule| match rule {
+                Rule::IpSet(set)
+                | Rule::PortFrom(PortFromRule {
+                    source: Source::IpSet(set),
+                    ..
+                }) => Some(set),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port_rule(s: &str) -> Result<PortRule, String> {
+        match Rule::from_str(s)? {
+            Rule::Port(rule) => Ok(rule),
+            other => panic!("{} parsed as {:?}", s, other),
+        }
+    }
+
+    #[test]
+    fn port_ranges() {
+        let rule = port_rule("8000-8100/tcp").unwrap();
+        assert_eq!(
+            rule.ports,
+            PortRange {
+                first: 8000,
+                last: 8100
+            }
+        );
+        assert_eq!(rule.ports.to_string(), "8000-8100");
+        assert_eq!(
+            port_rule("65535/udp").unwrap().ports,
+            PortRange::single(65535)
+        );
+
+        let err = port_rule("8100-8000/tcp").unwrap_err();
+        assert!(
+            err.contains("first port must not be above the last"),
+            "{}",
+            err
+        );
+        for rule in ["0/tcp", "0-10/tcp", "65536/udp", "1-65536/udp", "-5/tcp"] {
+            let err = port_rule(rule).unwrap_err();
+            assert!(err.contains("Invalid port number"), "{}: {}", rule, err);
+        }
+    }
+
+    #[test]
+    fn ip_rules() {
+        let rule = |s: &str| match Rule::from_str(s) {
+            Ok(Rule::Ip(ip)) => Ok(ip.to_string()),
+            Ok(other) => panic!("{} parsed as {:?}", s, other),
+            Err(e) => Err(e),
+        };
+
+        assert_eq!(rule("ip:192.0.2.0/24").unwrap(), "192.0.2.0/24");
+        assert_eq!(rule("ip:198.51.100.66").unwrap(), "198.51.100.66/32");
+        assert_eq!(rule("ip:2001:db8::1").unwrap(), "2001:db8::1/128");
+
+        let err = rule("ip:192.0.2.1/24").unwrap_err();
+        assert!(err.contains("did you mean 192.0.2.0/24?"), "{}", err);
+        let err = rule("ip:2001:db8::1/32").unwrap_err();
+        assert!(err.contains("did you mean 2001:db8::/32?"), "{}", err);
+
+        let err = rule("ip:10.0.0.0/33").unwrap_err();
+        assert!(err.contains("between 1 and 32"), "{}", err);
+        let err = rule("ip:::/129").unwrap_err();
+        assert!(err.contains("between 1 and 128"), "{}", err);
+        let err = rule("ip:0.0.0.0/0").unwrap_err();
+        assert!(err.contains("between 1 and 32"), "{}", err);
+        let err = rule("ip:example.com").unwrap_err();
+        assert!(err.contains("not an IPv4 or IPv6 address"), "{}", err);
+    }
+
+    #[test]
+    fn icmp_rules() {
+        let icmp = |s: &str| match Rule::from_str(s) {
+            Ok(Rule::Icmp(icmp)) => Ok((icmp.family, icmp.icmp_type)),
+            Ok(other) => panic!("{} parsed as {:?}", s, other),
+            Err(e) => Err(e),
+        };
+
+        assert_eq!(icmp("icmp:echo-request").unwrap(), (Family::V4, 8));
+        assert_eq!(icmp("icmpv6:packet-too-big").unwrap(), (Family::V6, 2));
+        assert_eq!(icmp("icmpv6:143").unwrap(), (Family::V6, 143));
+
+        let err = icmp("icmp:bogus").unwrap_err();
+        assert!(err.contains("unknown type 'bogus'"), "{}", err);
+        // ICMPv6 names only go with `icmpv6:`
+        let err = icmp("icmp:packet-too-big").unwrap_err();
+        assert!(err.contains("unknown type 'packet-too-big'"), "{}", err);
+        let err = icmp("icmp:256").unwrap_err();
+        assert!(err.contains("unknown type '256'"), "{}", err);
+    }
+
+    #[test]
+    fn any_expands_to_tcp_and_udp() {
+        for name in ["any", "tcp+udp"] {
+            let protocol = Protocol::from_str(name).unwrap();
+            assert_eq!(
+                protocol.expand(),
+                [Protocol::TCP, Protocol::UDP],
+                "{}",
+                name
+            );
+            assert_eq!(
+                port_rule(&format!("53/{}", name)).unwrap().protocol,
+                Protocol::Any
+            );
+        }
+        assert_eq!(Protocol::SCTP.expand(), [Protocol::SCTP]);
+        assert!(Protocol::from_str("icmp").is_err());
+    }
+
+    #[test]
+    fn merge_joins_touching_ranges() {
+        let merged = PortRange::merge([
+            PortRange::single(25),
+            PortRange {
+                first: 10,
+                last: 20,
+            },
+            PortRange::single(21),
+            PortRange {
+                first: 15,
+                last: 18,
+            },
+            PortRange::single(65535),
+        ]);
+        assert_eq!(
+            merged,
+            [
+                PortRange {
+                    first: 10,
+                    last: 21
+                },
+                PortRange::single(25),
+                PortRange::single(65535),
+            ]
+        );
+        assert!(PortRange::merge([]).is_empty());
+    }
 }